@@ -1,50 +1,322 @@
 use ansi_term::Colour;
 use chrono_tz::Europe::Moscow;
-use env_logger::Builder;
-use std::{fs::OpenOptions, io::Write};
+use env_logger::filter::{self, Filter};
+use env_logger::{Builder, Logger};
+use log::{info, Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-pub fn init() {
-    let log_file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("server.log")
-        .expect("Can't open server.log");
+use crate::server::config::{ColorMode, ServerConfig};
 
-    Builder::from_default_env()
+const LOG_PATH: &str = "server.log";
+
+static LEVEL_CHANGE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Действующий фильтр; заменяется целиком при смене уровня через SIGUSR1
+static FILTERS: RwLock<Option<LevelFilters>> = RwLock::new(None);
+
+/// Ошибка записи в файл лога сообщается в stderr один раз: при заполненном
+/// диске она повторялась бы на каждой записи
+static WRITE_ERROR_REPORTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigusr1(_: libc::c_int) {
+    LEVEL_CHANGE_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    backups: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, backups: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            backups,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_size > 0 && self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.backups == 0 {
+            self.file.set_len(0)?;
+            self.size = 0;
+            return Ok(());
+        }
+
+        for generation in (1..self.backups).rev() {
+            let from = self.backup(generation);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, self.backup(generation + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.backup(1))?;
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Путь резервной копии поколения `generation`: server.log.1, server.log.2...
+    fn backup(&self, generation: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", generation));
+        path.into()
+    }
+}
+
+/// Фильтр из RUST_LOG: директивы модулей сохраняются, а общий уровень
+/// заменяется выбранным при запуске (-q/-v) или через SIGUSR1
+struct LevelFilters {
+    spec: String,
+    level: LevelFilter,
+    filter: Filter,
+}
+
+impl LevelFilters {
+    fn new(spec: &str, level: LevelFilter) -> Self {
+        Self {
+            spec: spec.to_string(),
+            level,
+            filter: filter::Builder::new().parse(spec).filter_level(level).build(),
+        }
+    }
+
+    /// Общий уровень из RUST_LOG без учёта директив модулей (по умолчанию error)
+    fn global_level(spec: &str) -> LevelFilter {
+        let filter = filter::Builder::new().parse(spec).build();
+        [Level::Trace, Level::Debug, Level::Info, Level::Warn, Level::Error]
+            .into_iter()
+            .find(|&level| filter.enabled(&Metadata::builder().level(level).target("").build()))
+            .map_or(LevelFilter::Off, |level| level.to_level_filter())
+    }
+}
+
+/// env_logger без собственного фильтра: записи отбирает FILTERS
+struct RuntimeLogger(Logger);
+
+impl Log for RuntimeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        FILTERS
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|filters| filters.filter.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+pub fn init(config: &ServerConfig) {
+    let colored = match config.color {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => std::io::stderr().is_terminal(),
+    };
+
+    let log_file = Mutex::new(
+        RotatingFile::open(Path::new(LOG_PATH), config.log_max_size, config.log_backups)
+            .expect("Can't open server.log"),
+    );
+
+    // Общий уровень из RUST_LOG (или -q/-v) только начальный: SIGUSR1 меняет
+    // его поверх директив модулей
+    let spec = std::env::var(env_logger::DEFAULT_FILTER_ENV).unwrap_or_default();
+    let initial_level = match (config.quiet, config.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilters::global_level(&spec),
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+
+    let logger = Builder::new()
+        .filter_level(LevelFilter::Trace)
         .format(move |buf, record| {
             let timestamp = chrono::Utc::now()
                 .with_timezone(&Moscow)
                 .format("%Y-%m-%dT%H:%M:%SZ%:z");
 
-            let level = match record.level() {
-                log::Level::Error => Colour::Red.paint(record.level().to_string()),
-                log::Level::Warn => Colour::Yellow.paint(record.level().to_string()),
-                log::Level::Info => Colour::Green.paint(record.level().to_string()),
-                log::Level::Debug => Colour::Blue.paint(record.level().to_string()),
-                log::Level::Trace => Colour::Purple.paint(record.level().to_string()),
-            };
-
             let log_line = format!(
                 "[{} {} {}] {}",
                 timestamp,
-                level,
+                level_label(record.level(), colored),
                 record.module_path().unwrap_or_default(),
                 record.args()
             );
 
-            writeln!(
-                &log_file,
+            let written = log_file.lock().unwrap().write_line(&format!(
                 "[{} {} {}] {}",
                 timestamp,
                 record.level(),
                 record.module_path().unwrap_or_default(),
                 record.args()
-            )
-            .expect("Failed to write to log file");
+            ));
+            if let Err(e) = written
+                && !WRITE_ERROR_REPORTED.swap(true, Ordering::SeqCst)
+            {
+                eprintln!("Failed to write to {}: {}", LOG_PATH, e);
+            }
 
             writeln!(buf, "{}", log_line)?;
 
             Ok(())
         })
-        .init();
+        .build();
+
+    let filters = LevelFilters::new(&spec, initial_level);
+    let max_level = filters.filter.filter();
+    *FILTERS.write().unwrap() = Some(filters);
+    log::set_boxed_logger(Box::new(RuntimeLogger(logger))).expect("Logger already initialized");
+    log::set_max_level(max_level);
+
+    unsafe {
+        libc::signal(libc::SIGUSR1, on_sigusr1 as *const () as libc::sighandler_t);
+    }
+}
+
+/// Уровень записи для stderr; в файл он всегда пишется без цвета
+fn level_label(level: Level, colored: bool) -> String {
+    if !colored {
+        return level.to_string();
+    }
+
+    let colour = match level {
+        Level::Error => Colour::Red,
+        Level::Warn => Colour::Yellow,
+        Level::Info => Colour::Green,
+        Level::Debug => Colour::Blue,
+        Level::Trace => Colour::Purple,
+    };
+    colour.paint(level.to_string()).to_string()
+}
+
+/// Применяет смену уровня логирования, запрошенную через SIGUSR1:
+/// info -> debug -> trace -> info
+pub fn apply_pending_level_change() {
+    if !LEVEL_CHANGE_REQUESTED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    let mut filters = FILTERS.write().unwrap();
+    let Some(current) = filters.as_ref() else {
+        return;
+    };
+    let next = LevelFilters::new(&current.spec, next_level(current.level));
+    let level = next.level;
+    log::set_max_level(next.filter.filter());
+    *filters = Some(next);
+    drop(filters);
+
+    info!("Log level changed to {} via SIGUSR1", level);
+}
+
+fn next_level(level: LevelFilter) -> LevelFilter {
+    match level {
+        LevelFilter::Info => LevelFilter::Debug,
+        LevelFilter::Debug => LevelFilter::Trace,
+        _ => LevelFilter::Info,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_shifts_backups() {
+        let dir = std::env::temp_dir().join(format!("log-rotation-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.log");
+        // Строка занимает 10 байт с переводом строки: в файл помещаются две
+        let mut log = RotatingFile::open(&path, 20, 2).unwrap();
+        for i in 0..7 {
+            log.write_line(&format!("line {:04}", i)).unwrap();
+        }
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("server.log"), "line 0006\n");
+        assert_eq!(read("server.log.1"), "line 0004\nline 0005\n");
+        assert_eq!(read("server.log.2"), "line 0002\nline 0003\n");
+        assert!(!dir.join("server.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn level_cycling_keeps_module_directives() {
+        let enabled = |filters: &LevelFilters, target: &str, level: Level| {
+            filters
+                .filter
+                .enabled(&Metadata::builder().level(level).target(target).build())
+        };
+        let spec = "warn,static_server::server::handlers=trace,threadpool=off";
+        assert_eq!(LevelFilters::global_level(spec), LevelFilter::Warn);
+        assert_eq!(LevelFilters::global_level(""), LevelFilter::Error);
+
+        let mut filters = LevelFilters::new(spec, LevelFilter::Info);
+        for expected in [LevelFilter::Debug, LevelFilter::Trace, LevelFilter::Info] {
+            filters = LevelFilters::new(&filters.spec, next_level(filters.level));
+            assert_eq!(filters.level, expected);
+
+            let global = expected.to_level().unwrap();
+            assert!(enabled(&filters, "static_server::server", global));
+            assert_eq!(
+                enabled(&filters, "static_server::server", Level::Trace),
+                expected == LevelFilter::Trace
+            );
+            assert!(enabled(
+                &filters,
+                "static_server::server::handlers",
+                Level::Trace
+            ));
+            assert!(!enabled(&filters, "threadpool", Level::Error));
+            assert_eq!(filters.filter.filter(), LevelFilter::Trace);
+        }
+    }
+
+    #[test]
+    fn level_label_has_no_escapes_without_color() {
+        for level in [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ] {
+            assert_eq!(level_label(level, false), level.to_string());
+            assert!(level_label(level, true).contains('\x1b'));
+        }
+    }
 }
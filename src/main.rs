@@ -2,19 +2,35 @@ mod logger;
 mod server;
 mod static_files;
 
-use clap::Parser;
-use log::info;
+use log::{error, info};
 use server::HttpServer;
 use server::config::ServerConfig;
 
-fn main() -> std::io::Result<()> {
-    logger::init();
+fn main() {
+    let config = ServerConfig::from_args(std::env::args_os()).unwrap_or_else(|e| e.exit());
+    logger::init(&config);
+
+    if config.dry_run {
+        if let Err(e) = HttpServer::dry_run(&config) {
+            eprintln!("Dry run failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let config = ServerConfig::parse();
     info!("Starting Static HTTP Server with config: {:?}", config);
 
-    let server = HttpServer::new(&config)?;
+    let server = match HttpServer::new(&config) {
+        Ok(server) => server,
+        Err(e) => {
+            // Логгер дублирует ошибки в stderr; если он заглушён, печатаем сами
+            if log::log_enabled!(log::Level::Error) {
+                error!("Failed to start server: {}", e);
+            } else {
+                eprintln!("Failed to start server: {}", e);
+            }
+            std::process::exit(1);
+        }
+    };
     server.run();
-
-    Ok(())
 }
@@ -0,0 +1,330 @@
+use std::fs;
+use std::path::Path;
+
+use crate::static_files::autoindex_content;
+
+/// Шаблон страницы индекса: файл из --index-template или встроенный
+pub fn load_template(template_path: Option<&Path>) -> std::io::Result<String> {
+    let Some(path) = template_path else {
+        return Ok(autoindex_content::get_index_template());
+    };
+
+    let template = fs::read_to_string(path)?;
+    if !template.contains("{entries}") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Index template {:?} has no {{entries}} placeholder", path),
+        ));
+    }
+
+    Ok(template)
+}
+
+/// Список содержимого директории, подставленный в шаблон вместо `{path}` и `{entries}`.
+/// Записи отсортированы, поддиректории помечены завершающим `/`
+pub fn render(dir: &Path, url_path: &str, template: &str) -> std::io::Result<String> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        names.push(entry_name(&entry?)?);
+    }
+    names.sort();
+
+    let base = url_path.trim_end_matches('/');
+    let mut entries = String::new();
+
+    if !base.is_empty() {
+        let parent = &base[..base.rfind('/').unwrap_or(0) + 1];
+        entries.push_str(&format!(
+            "            <li><a href=\"{}\">../</a></li>\n",
+            escape_html(parent)
+        ));
+    }
+
+    for name in &names {
+        entries.push_str(&format!(
+            "            <li><a href=\"{}/{}\">{}</a></li>\n",
+            escape_html(base),
+            escape_html(name),
+            escape_html(name)
+        ));
+    }
+
+    Ok(fill_template(
+        template,
+        &[("{path}", &escape_html(url_path)), ("{entries}", entries.trim_end())],
+    ))
+}
+
+/// Сколько записей директории читается и сортируется за одну порцию
+const LISTING_BATCH: usize = 256;
+
+/// Потоковый список директории для chunked-ответа: записи читаются из
+/// `read_dir` порциями, поэтому память не зависит от размера директории.
+/// Сортировка выполняется только внутри порции, так что большие директории
+/// отдаются частично упорядоченными
+#[derive(Debug)]
+pub struct Listing {
+    entries: fs::ReadDir,
+    base: String,
+    tail: Option<String>,
+    pending: Vec<u8>,
+    sent: usize,
+}
+
+impl Listing {
+    pub fn open(dir: &Path, url_path: &str, template: &str) -> std::io::Result<Self> {
+        let entries = fs::read_dir(dir)?;
+        let path = escape_html(url_path);
+        let (head, tail) = template.split_once("{entries}").unwrap_or((template, ""));
+
+        let base = url_path.trim_end_matches('/').to_string();
+        let mut first = fill_template(head, &[("{path}", &path)]);
+        if !base.is_empty() {
+            let parent = &base[..base.rfind('/').unwrap_or(0) + 1];
+            first.push_str(&format!(
+                "            <li><a href=\"{}\">../</a></li>\n",
+                escape_html(parent)
+            ));
+        }
+
+        Ok(Self {
+            entries,
+            base,
+            tail: Some(fill_template(tail, &[("{path}", &path)])),
+            pending: encode_chunk(first.as_bytes()),
+            sent: 0,
+        })
+    }
+
+    /// Неотправленная часть текущего chunk'а; при необходимости читает
+    /// следующую порцию записей. Пустой срез - список отдан целиком
+    pub fn pending(&mut self) -> std::io::Result<&[u8]> {
+        if self.sent >= self.pending.len() {
+            self.refill()?;
+        }
+        Ok(&self.pending[self.sent..])
+    }
+
+    pub fn consume(&mut self, n: usize) {
+        self.sent += n;
+    }
+
+    fn refill(&mut self) -> std::io::Result<()> {
+        self.pending.clear();
+        self.sent = 0;
+
+        let mut names = Vec::new();
+        for entry in self.entries.by_ref().take(LISTING_BATCH) {
+            names.push(entry_name(&entry?)?);
+        }
+
+        if !names.is_empty() {
+            names.sort();
+            let mut html = String::new();
+            for name in &names {
+                html.push_str(&format!(
+                    "            <li><a href=\"{}/{}\">{}</a></li>\n",
+                    escape_html(&self.base),
+                    escape_html(name),
+                    escape_html(name)
+                ));
+            }
+            self.pending = encode_chunk(html.as_bytes());
+        } else if let Some(tail) = self.tail.take() {
+            self.pending = encode_chunk(tail.as_bytes());
+            self.pending.extend_from_slice(b"0\r\n\r\n");
+        }
+
+        Ok(())
+    }
+}
+
+fn entry_name(entry: &fs::DirEntry) -> std::io::Result<String> {
+    let mut name = entry.file_name().to_string_lossy().into_owned();
+    if entry.file_type()?.is_dir() {
+        name.push('/');
+    }
+    Ok(name)
+}
+
+/// Chunk для Transfer-Encoding: chunked; пустые данные не кодируются,
+/// так как chunk нулевой длины завершает тело
+fn encode_chunk(data: &[u8]) -> Vec<u8> {
+    if data.is_empty() {
+        return Vec::new();
+    }
+
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    chunk
+}
+
+/// Подстановка за один проход, чтобы "{entries}" в имени файла или пути
+/// не раскрывалось повторно
+fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some((start, placeholder, value)) = values
+        .iter()
+        .filter_map(|(placeholder, value)| {
+            rest.find(placeholder).map(|start| (start, *placeholder, *value))
+        })
+        .min_by_key(|(start, _, _)| *start)
+    {
+        result.push_str(&rest[..start]);
+        result.push_str(value);
+        rest = &rest[start + placeholder.len()..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn directory(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            match file.strip_suffix('/') {
+                Some(subdir) => fs::create_dir_all(dir.join(subdir)).unwrap(),
+                None => fs::write(dir.join(file), "").unwrap(),
+            }
+        }
+        dir
+    }
+
+    #[test]
+    fn render_sorts_and_escapes_entries() {
+        let dir = directory(
+            "autoindex-render",
+            &["b.txt", "a<b>.txt", "sub/", "\"q\"&.txt"],
+        );
+        let html = render(
+            &dir,
+            "/docs/<x>/",
+            "<title>{path}</title><ul>{entries}</ul>",
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(
+            html.starts_with("<title>/docs/&lt;x&gt;/</title><ul>"),
+            "{}",
+            html
+        );
+        let hrefs: Vec<&str> = html
+            .split("<a href=\"")
+            .skip(1)
+            .map(|rest| rest.split('"').next().unwrap())
+            .collect();
+        assert_eq!(
+            hrefs,
+            [
+                "/docs/",
+                "/docs/&lt;x&gt;/&quot;q&quot;&amp;.txt",
+                "/docs/&lt;x&gt;/a&lt;b&gt;.txt",
+                "/docs/&lt;x&gt;/b.txt",
+                "/docs/&lt;x&gt;/sub/",
+            ]
+        );
+        assert!(!html.contains("<b>"));
+    }
+
+    #[test]
+    fn load_template_requires_entries_placeholder() {
+        let dir = directory("autoindex-template", &[]);
+        let (valid, invalid) = (dir.join("valid.html"), dir.join("invalid.html"));
+        fs::write(&valid, "<ul>{entries}</ul>").unwrap();
+        fs::write(&invalid, "<ul>{path}</ul>").unwrap();
+
+        assert_eq!(load_template(Some(&valid)).unwrap(), "<ul>{entries}</ul>");
+        let error = load_template(Some(&invalid)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(load_template(None).unwrap().contains("{entries}"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn listing_streams_all_entries_in_batches() {
+        let names: Vec<String> = (0..LISTING_BATCH * 2 + 10)
+            .map(|i| format!("file-{:04}.txt", i))
+            .collect();
+        let files: Vec<&str> = names.iter().map(String::as_str).collect();
+        let dir = directory("autoindex-listing", &files);
+
+        let mut listing = Listing::open(&dir, "/big/", "<ul>{entries}</ul>").unwrap();
+        let mut body = Vec::new();
+        loop {
+            let pending = listing.pending().unwrap().to_vec();
+            if pending.is_empty() {
+                break;
+            }
+            // Отправка по частям, как при неполной записи в сокет
+            let n = pending.len().min(1000);
+            body.extend_from_slice(&pending[..n]);
+            listing.consume(n);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut chunks = Vec::new();
+        let mut rest = &body[..];
+        loop {
+            let line_end = rest.windows(2).position(|w| w == b"\r\n").unwrap();
+            let len =
+                usize::from_str_radix(std::str::from_utf8(&rest[..line_end]).unwrap(), 16).unwrap();
+            let data = &rest[line_end + 2..line_end + 2 + len];
+            assert_eq!(&rest[line_end + 2 + len..line_end + 4 + len], b"\r\n");
+            rest = &rest[line_end + 4 + len..];
+            if len == 0 {
+                break;
+            }
+            chunks.push(String::from_utf8(data.to_vec()).unwrap());
+        }
+        assert!(rest.is_empty());
+
+        // Первый chunk - начало шаблона со ссылкой на родителя, последний -
+        // его конец, между ними три порции записей
+        assert_eq!(
+            chunks.len(),
+            5,
+            "{:?}",
+            chunks.iter().map(String::len).collect::<Vec<_>>()
+        );
+        assert!(chunks[0].starts_with("<ul>"));
+        assert_eq!(chunks[4], "</ul>");
+        let html = chunks.concat();
+        for name in &names {
+            assert_eq!(
+                html.matches(&format!("\"/big/{}\"", name)).count(),
+                1,
+                "{}",
+                name
+            );
+        }
+        for batch in &chunks[1..4] {
+            let hrefs: Vec<&str> = batch.split("href=\"").skip(1).collect();
+            assert!(hrefs.is_sorted());
+        }
+    }
+}
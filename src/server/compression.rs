@@ -0,0 +1,89 @@
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::path::Path;
+
+use super::request::{HttpRequest, weighted_items};
+
+/// Файлы меньше этого размера не сжимаются: заголовок gzip съест весь выигрыш
+pub const MIN_COMPRESS_SIZE: u64 = 256;
+
+/// Файлы больше этого размера отдаются без сжатия на лету: сжатый ответ
+/// целиком держится в памяти до отправки
+pub const MAX_COMPRESS_SIZE: u64 = 8 * 1024 * 1024;
+
+pub fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || content_type.starts_with("application/javascript")
+        || content_type.starts_with("application/json")
+        || content_type.starts_with("image/svg+xml")
+}
+
+pub fn accepts_gzip(request: &HttpRequest) -> bool {
+    request
+        .header("Accept-Encoding")
+        .is_some_and(|value| encoding_quality(value, "gzip").is_some_and(|q| q > 0.0))
+}
+
+/// Клиент не запретил несжатое представление: без Accept-Encoding, а также
+/// если identity (явно или через `*`) не имеет веса 0
+pub fn accepts_identity(request: &HttpRequest) -> bool {
+    request
+        .header("Accept-Encoding")
+        .is_none_or(|value| encoding_quality(value, "identity").is_none_or(|q| q > 0.0))
+}
+
+/// Вес кодировки в Accept-Encoding с учётом `*`; `None`, если она не упомянута
+pub fn encoding_quality(accept_encoding: &str, encoding: &str) -> Option<f32> {
+    let mut wildcard = None;
+
+    for (name, quality) in weighted_items(accept_encoding) {
+        if name.eq_ignore_ascii_case(encoding) {
+            return Some(quality);
+        }
+        if name == "*" {
+            wildcard = Some(quality);
+        }
+    }
+
+    wildcard
+}
+
+/// Сжимает файл потоком: в памяти только результат, исходник целиком не читается
+pub fn gzip_file(path: &Path, level: u32) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+    std::io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut decoded).unwrap();
+        decoded
+    }
+
+    #[test]
+    fn gzip_file_honours_compression_level() {
+        let path = std::env::temp_dir().join(format!("gzip-level-{}.txt", std::process::id()));
+        let content: Vec<u8> = (0..20_000u32)
+            .flat_map(|i| {
+                format!("line {} value {}\n", i, i.wrapping_mul(2654435761) % 1000).into_bytes()
+            })
+            .collect();
+        std::fs::write(&path, &content).unwrap();
+
+        let fast = gzip_file(&path, 1).unwrap();
+        let best = gzip_file(&path, 9).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(best.len() < fast.len(), "{} >= {}", best.len(), fast.len());
+        assert_eq!(gunzip(&fast), content);
+        assert_eq!(gunzip(&best), content);
+    }
+}
@@ -1,48 +1,1246 @@
-use clap::Parser;
-use std::path::PathBuf;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use std::ffi::OsString;
+use std::fmt;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum RobotsPolicy {
+    Allow,
+    Deny,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum PoolModel {
+    /// Один пул потоков на чтение и запись
+    Shared,
+    /// Отдельные группы потоков для чтения запросов и отправки ответов
+    Split,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum EtagMode {
+    /// По размеру и времени изменения
+    Weak,
+    /// По хешу SHA-256 содержимого
+    Strong,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    Always,
+    Auto,
+    Never,
+}
+
+/// Политика Cache-Control для расширения файла (`--cache-control-ext ext=value`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheRule {
+    pub extension: String,
+    pub value: String,
+}
+
+/// Кодировка для расширения файла (`--charset-ext ext=charset`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct CharsetRule {
+    pub extension: String,
+    pub charset: String,
+}
+
+fn parse_charset(s: &str) -> Result<String, String> {
+    let charset = s.trim();
+    if charset.is_empty()
+        || !charset
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:+".contains(c))
+    {
+        return Err(format!("invalid charset '{}'", charset));
+    }
+    Ok(charset.to_ascii_lowercase())
+}
+
+fn parse_charset_rule(s: &str) -> Result<CharsetRule, String> {
+    let (extension, charset) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected ext=charset, got '{}'", s))?;
+
+    let extension = extension.trim().trim_start_matches('.');
+    if extension.is_empty() || extension.contains(['/', '.']) {
+        return Err(format!("invalid extension '{}'", extension));
+    }
+
+    Ok(CharsetRule {
+        extension: extension.to_ascii_lowercase(),
+        charset: parse_charset(charset)?,
+    })
+}
+
+fn parse_url_path(s: &str) -> Result<String, String> {
+    if !s.starts_with('/') || s.contains(char::is_whitespace) {
+        return Err(format!("'{}' is not an absolute URL path", s));
+    }
+    Ok(s.to_string())
+}
+
+fn parse_host(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '/') {
+        return Err(format!("'{}' is not a host name", s));
+    }
+    Ok(s.to_string())
+}
+
+/// Учётные данные USER:PASSWORD (`--admin-auth`). В отладочном выводе
+/// конфигурации (в том числе в строке запуска в логе) скрыты
+#[derive(Clone, PartialEq)]
+pub struct Credentials(String);
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"<redacted>\"")
+    }
+}
+
+impl Deref for Credentials {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+fn parse_credentials(s: &str) -> Result<Credentials, String> {
+    match s.split_once(':') {
+        Some((user, _)) if !user.is_empty() && !s.contains(['\r', '\n']) => {
+            Ok(Credentials(s.to_string()))
+        }
+        _ => Err("expected USER:PASSWORD".to_string()),
+    }
+}
+
+fn parse_cache_value(s: &str) -> Result<String, String> {
+    let value = s.trim();
+    if value.is_empty() || value.contains(['\r', '\n']) {
+        return Err(format!("invalid Cache-Control value '{}'", value));
+    }
+    Ok(value.to_string())
+}
+
+fn parse_cache_rule(s: &str) -> Result<CacheRule, String> {
+    let (extension, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected ext=value, got '{}'", s))?;
+
+    let extension = extension.trim().trim_start_matches('.');
+    if extension.is_empty() || extension.contains(['/', '.']) {
+        return Err(format!("invalid extension '{}'", extension));
+    }
+
+    Ok(CacheRule {
+        extension: extension.to_ascii_lowercase(),
+        value: parse_cache_value(value)?,
+    })
+}
+
+/// Ответ на `/`, если в корне нет index.html (`--root-response`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum RootResponse {
+    NotFound,
+    Autoindex,
+    Redirect(String),
+}
+
+fn parse_root_response(s: &str) -> Result<RootResponse, String> {
+    match s {
+        "404" => Ok(RootResponse::NotFound),
+        "autoindex" => Ok(RootResponse::Autoindex),
+        _ => match s.strip_prefix("redirect:") {
+            Some(location) if !location.is_empty() && !location.contains(['\r', '\n']) => {
+                Ok(RootResponse::Redirect(location.to_string()))
+            }
+            _ => Err(format!("expected 404, autoindex or redirect:URL, got '{}'", s)),
+        },
+    }
+}
+
+/// Дополнительный корень для URL-префикса (`--mount /assets=/var/assets`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mount {
+    pub prefix: String,
+    pub dir: PathBuf,
+}
+
+fn parse_mount(s: &str) -> Result<Mount, String> {
+    let (prefix, dir) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected PREFIX=DIR, got '{}'", s))?;
+
+    let prefix = prefix.trim().trim_end_matches('/');
+    if !prefix.starts_with('/') || prefix.contains("..") {
+        return Err(format!("invalid mount prefix '{}'", prefix));
+    }
+
+    let dir = dir.trim();
+    if dir.is_empty() {
+        return Err(format!("missing directory for mount '{}'", prefix));
+    }
+
+    Ok(Mount {
+        prefix: prefix.to_string(),
+        dir: PathBuf::from(dir),
+    })
+}
+
+/// Диапазон портов для `--port-range start-end` (включительно)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+fn parse_port_range(s: &str) -> Result<PortRange, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expected start-end, got '{}'", s))?;
+
+    let start: u16 = start
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid port '{}'", start))?;
+    let end: u16 = end
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid port '{}'", end))?;
+
+    if start == 0 || start > end {
+        return Err(format!("invalid port range '{}'", s));
+    }
+
+    Ok(PortRange { start, end })
+}
+
+/// Превращает строки файла конфигурации (`key = value` или `key`) в аргументы
+/// командной строки `--key value`
+fn config_file_args(path: &Path) -> Result<Vec<OsString>, clap::Error> {
+    let content = std::fs::read_to_string(path).map_err(|e| {
+        clap::Error::raw(
+            clap::error::ErrorKind::Io,
+            format!("cannot read config file {:?}: {}\n", path, e),
+        )
+    })?;
+
+    let mut args = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), Some(value.trim())),
+            None => (line, None),
+        };
+
+        args.push(format!("--{}", key.trim_start_matches('-')).into());
+        if let Some(value) = value {
+            args.push(value.trim_matches('"').into());
+        }
+    }
+
+    Ok(args)
+}
 
 #[derive(Parser, Debug, Clone)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_override_self = true)]
 pub struct ServerConfig {
+    /// Файл конфигурации: по одному параметру на строку (`cache-control = no-cache`).
+    /// Параметры командной строки имеют приоритет; файл перечитывается по SIGHUP
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Прочитать один запрос из stdin, вывести в stdout ответ, который
+    /// отдал бы сервер, и завершиться (сокет не открывается)
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+
     /// Хост сервера
     #[arg(long, default_value = "127.0.0.1")]
     pub host: String,
 
-    /// Порт сервера
+    /// Порт сервера; без флага берётся из переменной окружения PORT
     #[arg(short, long, default_value_t = 9898)]
     pub port: u16,
 
+    /// Режим PaaS (Heroku и подобные): без --host слушать 0.0.0.0
+    #[arg(long, default_value_t = false)]
+    pub paas: bool,
+
+    /// Диапазон портов (например, 9898-9910): используется первый свободный
+    #[arg(long, value_parser = parse_port_range, conflicts_with = "port")]
+    pub port_range: Option<PortRange>,
+
+    /// Количество повторных попыток bind, пока адрес занят (например, порт в TIME_WAIT)
+    #[arg(long, default_value_t = 0)]
+    pub bind_retries: u32,
+
+    /// Начальная задержка между попытками bind в миллисекундах (удваивается)
+    #[arg(long, default_value_t = 500)]
+    pub bind_retry_delay: u64,
+
     /// Количество рабочих потоков в пуле потоков
     #[arg(short, long, default_value_t = 10)]
     pub threads: usize,
 
+    /// Модель пула: shared (общий) или split (потоки делятся поровну
+    /// между чтением запросов и отправкой ответов)
+    #[arg(long, value_enum, default_value_t = PoolModel::Shared)]
+    pub pool_model: PoolModel,
+
+    /// Максимальная длина очереди задач пула при всех занятых потоках:
+    /// сверх неё готовые соединения откладываются до следующей итерации
+    #[arg(long, default_value_t = 64)]
+    pub max_queued_jobs: usize,
+
     /// Корневая директория с документами
     #[arg(short, long, default_value = "./static")]
     pub document_root: PathBuf,
 
+    /// Отдавать URL-префикс из другой директории: PREFIX=DIR, например
+    /// /assets=/var/assets. Можно указывать несколько раз
+    #[arg(long, value_parser = parse_mount)]
+    pub mount: Vec<Mount>,
+
+    /// Отдавать на любой GET/HEAD этот файл независимо от пути запроса
+    /// (например, страницу о техническом обслуживании)
+    #[arg(long)]
+    pub single_file: Option<PathBuf>,
+
+    /// Создавать корневую директорию, если она не существует
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    pub create_root: bool,
+
+    /// Не записывать страницу по умолчанию (index.html и style.css)
+    #[arg(long, default_value_t = false)]
+    pub no_default_files: bool,
+
+    /// Записывать пустой index.html вместо встроенной страницы о шахматах
+    #[arg(long, default_value_t = false)]
+    pub blank_default: bool,
+
+    /// Следовать символическим ссылкам внутри корня документов (цель ссылки
+    /// всё равно должна лежать внутри корня); по умолчанию ссылки дают 403
+    #[arg(long, default_value_t = false)]
+    pub follow_symlinks: bool,
+
+    /// Показывать список файлов для директорий без index.html
+    #[arg(long, default_value_t = false)]
+    pub autoindex: bool,
+
+    /// HTML-шаблон списка файлов с подстановками {path} и {entries}
+    /// (по умолчанию - встроенный)
+    #[arg(long)]
+    pub index_template: Option<PathBuf>,
+
+    /// Ответ на `/`, если в корне нет index.html: 404, autoindex (список
+    /// файлов корня) или redirect:URL
+    #[arg(long, default_value = "404", value_parser = parse_root_response)]
+    pub root_response: RootResponse,
+
+    /// Отдавать сгенерированный robots.txt (allow или deny), если его нет в корне
+    #[arg(long, value_enum)]
+    pub robots: Option<RobotsPolicy>,
+
+    /// Сколько соединений принимать за одну итерацию цикла
+    #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u32).range(1..))]
+    pub accept_batch: u32,
+
+    /// Размер буфера отправки сокета (SO_SNDBUF) в байтах для принятых соединений
+    /// (по умолчанию - системный)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1024..=1073741824))]
+    pub so_sndbuf: Option<u32>,
+
+    /// Размер буфера приёма сокета (SO_RCVBUF) в байтах для принятых соединений
+    /// (по умолчанию - системный)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1024..=1073741824))]
+    pub so_rcvbuf: Option<u32>,
+
+    /// Включить TCP Fast Open на слушающем сокете (Linux): данные первого
+    /// запроса повторного клиента приходят вместе с SYN
+    #[arg(long, default_value_t = false)]
+    pub tcp_fastopen: bool,
+
     /// Максимальное количество одновременных соединений
     #[arg(long, default_value_t = 1000)]
     pub max_connections: usize,
 
+    /// Запас дескрипторов до мягкого RLIMIT_NOFILE: новые соединения получают
+    /// 503, пока число соединений плюс запас не меньше лимита. Запас покрывает
+    /// открытые для ответов файлы, журналы и слушающий сокет
+    #[arg(long, default_value_t = 64)]
+    pub fd_headroom: usize,
+
     /// Максимальный размер файла в байтах (по умолчанию: 128 МБ)
     #[arg(long, default_value_t = 134217728)] // 128 * 1024 * 1024
     pub max_file_size: u64,
 
+    /// Максимальный размер тела входящего запроса (PUT) в байтах (по умолчанию: 128 МБ)
+    #[arg(long, default_value_t = 134217728)] // 128 * 1024 * 1024
+    pub max_body_size: u64,
+
+    /// Применять --max-file-size к объёму отдаваемых байт, а не к размеру файла:
+    /// Range-запросы к большим файлам разрешены, если диапазон не превышает лимит,
+    /// а полный GET такого файла по-прежнему получает 413
+    #[arg(long, default_value_t = false)]
+    pub range_exempts_size_limit: bool,
+
+    /// Общий бюджет памяти под буферизованные (сгенерированные) ответы в байтах
+    /// (по умолчанию: 64 МБ)
+    #[arg(long, default_value_t = 67108864)] // 64 * 1024 * 1024
+    pub max_response_buffer: usize,
+
+    /// Сжимать текстовые ответы gzip на лету, если клиент это поддерживает
+    #[arg(long, default_value_t = false)]
+    pub gzip: bool,
+
+    /// Для HEAD сжимать файл, чтобы сообщить длину и кодировку gzip-ответа;
+    /// по умолчанию HEAD описывает несжатое представление
+    #[arg(long, default_value_t = false)]
+    pub head_compressed_length: bool,
+
+    /// Отдавать готовый file.gz вместо file клиентам, принимающим gzip
+    /// (Range-запросы применяются к байтам сжатого файла)
+    #[arg(long, default_value_t = false)]
+    pub gzip_static: bool,
+
+    /// Отдавать hero.avif или hero.webp вместо hero.jpg (png, gif), если
+    /// такой файл лежит рядом, а клиент явно перечислил тип в Accept
+    #[arg(long, default_value_t = false)]
+    pub image_variants: bool,
+
+    /// Отвечать 406, если клиент запретил identity (`identity;q=0` или
+    /// `*;q=0`), а сжатого представления нет; по умолчанию отдаётся identity
+    #[arg(long, default_value_t = false)]
+    pub strict_encoding: bool,
+
+    /// Уровень сжатия gzip: 0 (без сжатия) - 9 (максимальное)
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=9))]
+    pub compression_level: u32,
+
+    /// Кодировка, добавляемая к текстовым Content-Type (text/*)
+    #[arg(long, default_value = "utf-8", value_parser = parse_charset)]
+    pub default_charset: String,
+
+    /// Кодировка для расширения в виде ext=charset, перекрывает --default-charset
+    /// (можно указывать несколько раз)
+    #[arg(long, value_parser = parse_charset_rule)]
+    pub charset_ext: Vec<CharsetRule>,
+
+    /// Определять Content-Type по содержимому файла, если расширение неизвестно
+    #[arg(long, default_value_t = false)]
+    pub sniff_content_type: bool,
+
+    /// Способ вычисления ETag: weak (размер и mtime) или strong (хеш содержимого,
+    /// одинаковый на всех серверах для одинаковых файлов)
+    #[arg(long, value_enum, default_value_t = EtagMode::Weak)]
+    pub etag: EtagMode,
+
+    /// Отвечать 204 No Content (без тела и Content-Length) на запрос пустого файла
+    #[arg(long, default_value_t = false)]
+    pub empty_as_204: bool,
+
+    /// Значение Cache-Control для отдаваемых файлов (по умолчанию заголовок не отправляется)
+    #[arg(long, value_parser = parse_cache_value)]
+    pub cache_control: Option<String>,
+
+    /// Cache-Control для расширения в виде ext=value, перекрывает --cache-control
+    /// (можно указывать несколько раз)
+    #[arg(long, value_parser = parse_cache_rule)]
+    pub cache_control_ext: Vec<CacheRule>,
+
+    /// Origin, которому разрешены кросс-доменные запросы (CORS); "*" - любой.
+    /// Можно указывать несколько раз
+    #[arg(long)]
+    pub cors_origin: Vec<String>,
+
+    /// Access-Control-Max-Age в секундах для ответов на CORS preflight:
+    /// сколько браузер может не повторять предварительный запрос
+    #[arg(long)]
+    pub cors_max_age: Option<u64>,
+
+    /// Доверять заголовкам Forwarded / X-Forwarded-* от обратного прокси:
+    /// адрес клиента в логе берётся из них
+    #[arg(long, default_value_t = false)]
+    pub trust_proxy: bool,
+
+    /// Канонический хост (например, example.com): GET и HEAD с другим Host
+    /// получают 301 на тот же путь на этом хосте
+    #[arg(long, value_parser = parse_host)]
+    pub canonical_host: Option<String>,
+
+    /// Включать в тело 500 путь и текст ошибки (только для локальной
+    /// отладки; по умолчанию подробности пишутся лишь в лог)
+    #[arg(long, default_value_t = false)]
+    pub debug_errors: bool,
+
+    /// Путь отладочной страницы со списком соединений в JSON (по умолчанию отключена),
+    /// например /debug/connections. С --admin-auth требует Basic-авторизации
+    #[arg(long, value_parser = parse_url_path)]
+    pub debug_path: Option<String>,
+
+    /// Путь, POST на который с Basic-авторизацией --admin-auth плавно
+    /// останавливает сервер (по умолчанию отключён), например /admin/shutdown
+    #[arg(long, value_parser = parse_url_path)]
+    pub admin_shutdown_path: Option<String>,
+
+    /// Учётные данные USER:PASSWORD для административных запросов
+    #[arg(long, value_parser = parse_credentials)]
+    pub admin_auth: Option<Credentials>,
+
+    /// Максимальная длина пути запроса в байтах; более длинные пути
+    /// отклоняются с 414
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u64).range(1..=4096))]
+    pub max_path_length: u64,
+
+    /// Бюджет разбора заголовков запроса в миллисекундах: при превышении
+    /// разбор прерывается с 400
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_parse_time: u64,
+
+    /// Максимальное количество строк заголовков в запросе
+    #[arg(long, default_value_t = 100)]
+    pub max_headers: usize,
+
+    /// Требовать строгие окончания строк CRLF в заголовках (голый LF - 400)
+    #[arg(long, default_value_t = false)]
+    pub strict_crlf: bool,
+
+    /// Склеивать устаревший перенос заголовков (obs-fold) вместо ответа 400
+    #[arg(long, default_value_t = false)]
+    pub allow_obs_fold: bool,
+
+    /// Максимальное количество диапазонов в одном Range-запросе
+    #[arg(long, default_value_t = 16)]
+    pub max_ranges: usize,
+
+    /// Разрешить изменение файлов: загрузку PUT и удаление DELETE
+    #[arg(long, default_value_t = false)]
+    pub writable: bool,
+
+    /// Директория для временных файлов загрузок PUT (по умолчанию - директория цели).
+    /// Должна находиться на той же файловой системе, что и корень документов
+    #[arg(long)]
+    pub upload_tmp_dir: Option<PathBuf>,
+
+    /// Таймаут простоя keep-alive соединения в секундах
+    #[arg(long, default_value_t = 5)]
+    pub keepalive_timeout: u64,
+
+    /// Таймаут получения запроса (строки, заголовков, тела) в секундах
+    #[arg(long, default_value_t = 30)]
+    pub recv_timeout: u64,
+
+    /// Таймаут отправки ответа в секундах: соединение закрывается, если
+    /// клиент столько времени не принимает данные
+    #[arg(long, default_value_t = 30)]
+    pub send_timeout: u64,
+
+    /// Дополнительно выставлять --recv-timeout и --send-timeout принятым
+    /// сокетам как SO_RCVTIMEO/SO_SNDTIMEO. Сокеты неблокирующие, поэтому
+    /// основным механизмом остаётся обход соединений по last_activity;
+    /// опции страхуют блокирующие операции ввода-вывода
+    #[arg(long, default_value_t = false)]
+    pub socket_timeouts: bool,
+
+    /// Максимальный возраст соединения в секундах: после него текущий ответ
+    /// дописывается с `Connection: close`, а простаивающее соединение
+    /// закрывается (по умолчанию не ограничен)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_connection_age: Option<u64>,
+
+    /// Максимальное количество запросов в одном keep-alive соединении
+    #[arg(long, default_value_t = 100)]
+    pub keepalive_max: usize,
+
+    /// Завершить работу, если столько секунд нет ни одного соединения
+    /// (по умолчанию сервер работает бесконечно)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub exit_on_idle: Option<u64>,
+
     /// Таймаут pselect в секундах
     #[arg(long, default_value_t = 1)]
     pub select_timeout: u64,
+
+    /// Только ошибки в логе (вместо RUST_LOG)
+    #[arg(short, long, default_value_t = false, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Подробнее лог вместо RUST_LOG: -v info, -vv debug, -vvv trace
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Максимальный размер server.log в байтах до ротации (0 - без ротации)
+    #[arg(long, default_value_t = 10485760)] // 10 * 1024 * 1024
+    pub log_max_size: u64,
+
+    /// Количество хранимых ротированных файлов server.log.N
+    #[arg(long, default_value_t = 5)]
+    pub log_backups: usize,
+
+    /// Цветной вывод логов: always, auto (только в терминал) или never
+    #[arg(long, value_enum, default_value_t = ColorMode::Auto)]
+    pub color: ColorMode,
+
+    /// Файл для записи сырых байт запросов и ответов с отметкой времени и fd
+    /// (по умолчанию отключено)
+    #[arg(long)]
+    pub trace_file: Option<PathBuf>,
+
+    /// Максимальный размер файла трассировки в байтах, после которого запись
+    /// прекращается (0 - без ограничения)
+    #[arg(long, default_value_t = 104857600)] // 100 * 1024 * 1024
+    pub trace_max_size: u64,
+
+    /// Сколько байт каждого запроса и ответа сохранять в трассировке
+    /// (0 - целиком)
+    #[arg(long, default_value_t = 0)]
+    pub trace_max_bytes: u64,
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
+            config: None,
             host: "127.0.0.1".to_string(),
+            paas: false,
+            dry_run: false,
             port: 9898,
+            port_range: None,
+            bind_retries: 0,
+            bind_retry_delay: 500,
             threads: 10,
+            pool_model: PoolModel::Shared,
+            max_queued_jobs: 64,
             document_root: PathBuf::from("./static"),
+            mount: Vec::new(),
+            single_file: None,
+            create_root: true,
+            no_default_files: false,
+            blank_default: false,
+            follow_symlinks: false,
+            autoindex: false,
+            index_template: None,
+            root_response: RootResponse::NotFound,
+            robots: None,
+            accept_batch: 64,
+            so_sndbuf: None,
+            so_rcvbuf: None,
+            tcp_fastopen: false,
             max_connections: 1000,
+            fd_headroom: 64,
             max_file_size: 134217728,
+            max_body_size: 134217728,
+            range_exempts_size_limit: false,
+            max_response_buffer: 67108864,
+            gzip: false,
+            head_compressed_length: false,
+            gzip_static: false,
+            image_variants: false,
+            strict_encoding: false,
+            compression_level: 6,
+            default_charset: "utf-8".to_string(),
+            charset_ext: Vec::new(),
+            sniff_content_type: false,
+            etag: EtagMode::Weak,
+            empty_as_204: false,
+            cache_control: None,
+            cache_control_ext: Vec::new(),
+            cors_origin: Vec::new(),
+            cors_max_age: None,
+            trust_proxy: false,
+            canonical_host: None,
+            debug_errors: false,
+            debug_path: None,
+            admin_shutdown_path: None,
+            admin_auth: None,
+            max_path_length: 1024,
+            max_parse_time: 50,
+            max_headers: 100,
+            strict_crlf: false,
+            allow_obs_fold: false,
+            max_ranges: 16,
+            writable: false,
+            upload_tmp_dir: None,
+            keepalive_timeout: 5,
+            recv_timeout: 30,
+            send_timeout: 30,
+            socket_timeouts: false,
+            max_connection_age: None,
+            keepalive_max: 100,
+            exit_on_idle: None,
             select_timeout: 1,
+            quiet: false,
+            verbose: 0,
+            log_max_size: 10485760,
+            log_backups: 5,
+            color: ColorMode::Auto,
+            trace_file: None,
+            trace_max_size: 104857600,
+            trace_max_bytes: 0,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Разбирает командную строку, подставляя перед ней параметры из --config
+    pub fn from_args<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        let (cli, matches) = Self::parse_matches(&args)?;
+
+        let (mut config, matches) = match cli.config.as_deref() {
+            Some(path) => {
+                let mut merged = args[..1].to_vec();
+                merged.extend(config_file_args(path)?);
+                merged.extend_from_slice(&args[1..]);
+                Self::parse_matches(merged)?
+            }
+            None => (cli, matches),
+        };
+
+        config.apply_environment(&matches, std::env::var_os("PORT"))?;
+        config.validate().map_err(|message| {
+            clap::Error::raw(clap::error::ErrorKind::ArgumentConflict, format!("{}\n", message))
+        })?;
+        Ok(config)
+    }
+
+    fn parse_matches<I, T>(args: I) -> Result<(Self, ArgMatches), clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Self::command().try_get_matches_from(args)?;
+        let config = Self::from_arg_matches(&matches)?;
+        Ok((config, matches))
+    }
+
+    /// Значения, не заданные ни флагом, ни в --config: порт из PORT
+    /// (`port_env`) и 0.0.0.0 вместо хоста по умолчанию в режиме --paas
+    fn apply_environment(
+        &mut self,
+        matches: &ArgMatches,
+        port_env: Option<OsString>,
+    ) -> Result<(), clap::Error> {
+        let is_default = |id: &str| matches.value_source(id) == Some(ValueSource::DefaultValue);
+
+        if is_default("port")
+            && self.port_range.is_none()
+            && let Some(value) = port_env
+        {
+            self.port = value.to_str().and_then(|port| port.parse().ok()).ok_or_else(|| {
+                clap::Error::raw(
+                    clap::error::ErrorKind::InvalidValue,
+                    format!("invalid port {:?} in the PORT environment variable\n", value),
+                )
+            })?;
+        }
+
+        if self.paas && is_default("host") {
+            self.host = "0.0.0.0".to_string();
+        }
+
+        Ok(())
+    }
+
+    /// Отклоняет сочетания параметров, которые противоречат друг другу или
+    /// заведомо не действуют:
+    /// - --no-default-files и --blank-default: пустую страницу некуда записать;
+    /// - --head-compressed-length без --gzip: сжатого представления нет;
+    /// - --index-template без --autoindex и --root-response autoindex:
+    ///   шаблон никогда не используется;
+    /// - --upload-tmp-dir без --writable: загрузки запрещены;
+    /// - --cors-max-age без --cors-origin: preflight-ответов не бывает;
+    /// - --admin-shutdown-path без --admin-auth: остановить сервер мог бы кто угодно;
+    /// - --single-file с --autoindex или --root-response: путь запроса
+    ///   не используется, и списки директорий и ответ для `/` недостижимы
+    pub fn validate(&self) -> Result<(), String> {
+        let root_autoindex = self.root_response == RootResponse::Autoindex;
+
+        if self.no_default_files && self.blank_default {
+            return Err("--blank-default has no effect with --no-default-files".to_string());
+        }
+        if self.head_compressed_length && !self.gzip {
+            return Err("--head-compressed-length requires --gzip".to_string());
+        }
+        if self.index_template.is_some() && !self.autoindex && !root_autoindex {
+            return Err(
+                "--index-template requires --autoindex or --root-response autoindex".to_string(),
+            );
+        }
+        if self.upload_tmp_dir.is_some() && !self.writable {
+            return Err("--upload-tmp-dir requires --writable".to_string());
+        }
+        if self.cors_max_age.is_some() && self.cors_origin.is_empty() {
+            return Err("--cors-max-age requires --cors-origin".to_string());
+        }
+        if self.admin_shutdown_path.is_some() && self.admin_auth.is_none() {
+            return Err("--admin-shutdown-path requires --admin-auth".to_string());
+        }
+        if self.single_file.is_some()
+            && (self.autoindex || self.root_response != RootResponse::NotFound)
+        {
+            return Err(
+                "--single-file serves one file for every path and cannot be combined with \
+                 --autoindex or --root-response"
+                    .to_string(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Копия текущей конфигурации с применёнными изменениями из `fresh`:
+    /// переносятся только параметры, которые можно менять без перезапуска
+    pub fn hot_reloaded(&self, fresh: ServerConfig) -> ServerConfig {
+        ServerConfig {
+            root_response: fresh.root_response,
+            robots: fresh.robots,
+            follow_symlinks: fresh.follow_symlinks,
+            mount: fresh.mount,
+            single_file: fresh.single_file,
+            accept_batch: fresh.accept_batch,
+            so_sndbuf: fresh.so_sndbuf,
+            so_rcvbuf: fresh.so_rcvbuf,
+            max_file_size: fresh.max_file_size,
+            max_body_size: fresh.max_body_size,
+            range_exempts_size_limit: fresh.range_exempts_size_limit,
+            gzip: fresh.gzip,
+            head_compressed_length: fresh.head_compressed_length,
+            gzip_static: fresh.gzip_static,
+            image_variants: fresh.image_variants,
+            strict_encoding: fresh.strict_encoding,
+            compression_level: fresh.compression_level,
+            default_charset: fresh.default_charset,
+            charset_ext: fresh.charset_ext,
+            sniff_content_type: fresh.sniff_content_type,
+            etag: fresh.etag,
+            empty_as_204: fresh.empty_as_204,
+            cache_control: fresh.cache_control,
+            cache_control_ext: fresh.cache_control_ext,
+            cors_origin: fresh.cors_origin,
+            cors_max_age: fresh.cors_max_age,
+            trust_proxy: fresh.trust_proxy,
+            canonical_host: fresh.canonical_host,
+            debug_errors: fresh.debug_errors,
+            debug_path: fresh.debug_path,
+            admin_shutdown_path: fresh.admin_shutdown_path,
+            admin_auth: fresh.admin_auth,
+            max_path_length: fresh.max_path_length,
+            max_parse_time: fresh.max_parse_time,
+            max_headers: fresh.max_headers,
+            strict_crlf: fresh.strict_crlf,
+            allow_obs_fold: fresh.allow_obs_fold,
+            max_ranges: fresh.max_ranges,
+            writable: fresh.writable,
+            keepalive_timeout: fresh.keepalive_timeout,
+            recv_timeout: fresh.recv_timeout,
+            send_timeout: fresh.send_timeout,
+            socket_timeouts: fresh.socket_timeouts,
+            max_connection_age: fresh.max_connection_age,
+            keepalive_max: fresh.keepalive_max,
+            exit_on_idle: fresh.exit_on_idle,
+            ..self.clone()
+        }
+    }
+
+    /// Изменённые в `fresh` параметры, которые вступят в силу только после перезапуска
+    pub fn restart_required_changes(&self, fresh: &ServerConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+
+        macro_rules! compare {
+            ($($field:ident),* $(,)?) => {
+                $(
+                    if self.$field != fresh.$field {
+                        changed.push(stringify!($field));
+                    }
+                )*
+            };
+        }
+
+        compare!(
+            host,
+            port,
+            paas,
+            port_range,
+            bind_retries,
+            bind_retry_delay,
+            threads,
+            pool_model,
+            max_queued_jobs,
+            document_root,
+            create_root,
+            no_default_files,
+            blank_default,
+            autoindex,
+            index_template,
+            max_connections,
+            fd_headroom,
+            max_response_buffer,
+            upload_tmp_dir,
+            select_timeout,
+            tcp_fastopen,
+            quiet,
+            verbose,
+            log_max_size,
+            log_backups,
+            color,
+            trace_file,
+            trace_max_size,
+            trace_max_bytes,
+        );
+
+        changed
+    }
+
+    /// Действующие ограничения для отладочной страницы. Перечисляются явно,
+    /// поэтому учётные данные (--admin-auth) в вывод не попадают
+    pub fn limits_json(&self) -> String {
+        let optional = |value: Option<u64>| value.map_or("null".to_string(), |v| v.to_string());
+        format!(
+            "{{\"accept_batch\":{},\"keepalive_max\":{},\"keepalive_timeout\":{},\"max_body_size\":{},\"max_connection_age\":{},\"max_connections\":{},\"max_file_size\":{},\"max_headers\":{},\"max_parse_time\":{},\"max_path_length\":{},\"max_queued_jobs\":{},\"max_ranges\":{},\"max_response_buffer\":{},\"recv_timeout\":{},\"send_timeout\":{},\"threads\":{}}}",
+            self.accept_batch,
+            self.keepalive_max,
+            self.keepalive_timeout,
+            self.max_body_size,
+            optional(self.max_connection_age),
+            self.max_connections,
+            self.max_file_size,
+            self.max_headers,
+            self.max_parse_time,
+            self.max_path_length,
+            self.max_queued_jobs,
+            self.max_ranges,
+            self.max_response_buffer,
+            self.recv_timeout,
+            self.send_timeout,
+            self.threads
+        )
+    }
+
+    /// Корень и путь внутри него для пути запроса: самый длинный подходящий
+    /// --mount, иначе --document-root
+    pub fn mount_for<'a>(&'a self, path: &'a str) -> (&'a Path, &'a str) {
+        self.mount
+            .iter()
+            .filter_map(|mount| {
+                let rest = path.strip_prefix(mount.prefix.as_str())?;
+                (rest.is_empty() || rest.starts_with('/')).then_some((mount, rest))
+            })
+            .max_by_key(|(mount, _)| mount.prefix.len())
+            .map(|(mount, rest)| (mount.dir.as_path(), if rest.is_empty() { "/" } else { rest }))
+            .unwrap_or((self.document_root.as_path(), path))
+    }
+
+    /// Порты, которые сервер пробует по порядку при запуске
+    pub fn ports(&self) -> std::ops::RangeInclusive<u16> {
+        match self.port_range {
+            Some(range) => range.start..=range.end,
+            None => self.port..=self.port,
+        }
+    }
+
+    /// Кодировка текстового файла: правило по расширению, иначе --default-charset
+    pub fn charset_for(&self, path: &Path) -> &str {
+        let extension = path.extension().and_then(|ext| ext.to_str());
+
+        extension
+            .and_then(|ext| {
+                self.charset_ext
+                    .iter()
+                    .rev()
+                    .find(|rule| rule.extension.eq_ignore_ascii_case(ext))
+            })
+            .map(|rule| rule.charset.as_str())
+            .unwrap_or(&self.default_charset)
+    }
+
+    /// Cache-Control для файла: правило по расширению, иначе глобальное значение
+    pub fn cache_control_for(&self, path: &Path) -> Option<&str> {
+        let extension = path.extension().and_then(|ext| ext.to_str());
+
+        extension
+            .and_then(|ext| {
+                self.cache_control_ext
+                    .iter()
+                    .rev()
+                    .find(|rule| rule.extension.eq_ignore_ascii_case(ext))
+            })
+            .map(|rule| rule.value.as_str())
+            .or(self.cache_control.as_deref())
+    }
+}
+
+/// Текущая конфигурация, заменяемая целиком при перезагрузке по SIGHUP.
+/// Обработчики берут снимок через `load` и работают с ним до конца запроса
+pub struct SharedConfig {
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl SharedConfig {
+    pub fn new(config: ServerConfig) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    pub fn store(&self, config: ServerConfig) {
+        *self.current.write().unwrap() = Arc::new(config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(config: ServerConfig, flag: &str) {
+        let message = config.validate().expect_err(flag);
+        assert!(
+            message.contains(flag),
+            "{:?} does not mention {}",
+            message,
+            flag
+        );
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(ServerConfig::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_conflicting_combinations() {
+        let base = ServerConfig::default;
+
+        rejected(
+            ServerConfig {
+                no_default_files: true,
+                blank_default: true,
+                ..base()
+            },
+            "--blank-default",
+        );
+        rejected(
+            ServerConfig {
+                head_compressed_length: true,
+                gzip: false,
+                ..base()
+            },
+            "--head-compressed-length",
+        );
+        rejected(
+            ServerConfig {
+                index_template: Some(PathBuf::from("index.tpl")),
+                ..base()
+            },
+            "--index-template",
+        );
+        rejected(
+            ServerConfig {
+                upload_tmp_dir: Some(PathBuf::from("/tmp")),
+                writable: false,
+                ..base()
+            },
+            "--upload-tmp-dir",
+        );
+        rejected(
+            ServerConfig {
+                cors_max_age: Some(600),
+                ..base()
+            },
+            "--cors-max-age",
+        );
+        rejected(
+            ServerConfig {
+                admin_shutdown_path: Some("/admin/stop".to_string()),
+                ..base()
+            },
+            "--admin-shutdown-path",
+        );
+        rejected(
+            ServerConfig {
+                single_file: Some(PathBuf::from("app.html")),
+                autoindex: true,
+                ..base()
+            },
+            "--single-file",
+        );
+        rejected(
+            ServerConfig {
+                single_file: Some(PathBuf::from("app.html")),
+                root_response: RootResponse::Autoindex,
+                ..base()
+            },
+            "--single-file",
+        );
+    }
+
+    #[test]
+    fn accepts_combinations_with_their_requirements() {
+        let base = ServerConfig::default;
+
+        let valid = [
+            ServerConfig {
+                head_compressed_length: true,
+                gzip: true,
+                ..base()
+            },
+            ServerConfig {
+                index_template: Some(PathBuf::from("index.tpl")),
+                root_response: RootResponse::Autoindex,
+                ..base()
+            },
+            ServerConfig {
+                upload_tmp_dir: Some(PathBuf::from("/tmp")),
+                writable: true,
+                ..base()
+            },
+            ServerConfig {
+                cors_max_age: Some(600),
+                cors_origin: vec!["*".to_string()],
+                ..base()
+            },
+            ServerConfig {
+                admin_shutdown_path: Some("/admin/stop".to_string()),
+                admin_auth: Some(Credentials("admin:secret".to_string())),
+                ..base()
+            },
+            ServerConfig {
+                single_file: Some(PathBuf::from("app.html")),
+                ..base()
+            },
+        ];
+        for config in valid {
+            assert_eq!(config.validate(), Ok(()));
         }
     }
+
+    #[test]
+    fn admin_credentials_are_redacted() {
+        let config = ServerConfig::from_args([
+            "static-server",
+            "--admin-shutdown-path",
+            "/admin/shutdown",
+            "--admin-auth",
+            "admin:s3cret",
+        ])
+        .unwrap();
+        assert_eq!(config.admin_auth.as_deref(), Some("admin:s3cret"));
+
+        let startup = format!("Starting Static HTTP Server with config: {:?}", config);
+        assert!(!startup.contains("s3cret"), "{}", startup);
+        assert!(startup.contains("admin_auth: Some(\"<redacted>\")"));
+        assert!(!config.limits_json().contains("s3cret"));
+    }
+
+    #[test]
+    fn cache_control_by_extension_overrides_global_value() {
+        let config = ServerConfig::from_args([
+            "static-server",
+            "--cache-control",
+            "no-cache",
+            "--cache-control-ext",
+            "css=max-age=3600",
+            "--cache-control-ext",
+            ".CSS=public, max-age=60",
+            "--cache-control-ext",
+            "js=immutable",
+        ])
+        .unwrap();
+        let cache_control = |path: &str| config.cache_control_for(Path::new(path));
+
+        assert_eq!(cache_control("style.css"), Some("public, max-age=60"));
+        assert_eq!(cache_control("app.JS"), Some("immutable"));
+        assert_eq!(cache_control("index.html"), Some("no-cache"));
+        assert_eq!(cache_control("Makefile"), Some("no-cache"));
+
+        let per_extension =
+            ServerConfig::from_args(["static-server", "--cache-control-ext", "css=max-age=3600"])
+                .unwrap();
+        assert_eq!(
+            per_extension.cache_control_for(Path::new("a.css")),
+            Some("max-age=3600")
+        );
+        assert_eq!(per_extension.cache_control_for(Path::new("a.html")), None);
+    }
+
+    #[test]
+    fn mount_for_picks_longest_matching_prefix() {
+        let config = ServerConfig::from_args([
+            "static-server",
+            "-d",
+            "/srv/root",
+            "--mount",
+            "/a=/srv/a",
+            "--mount",
+            "/a/b/=/srv/ab",
+            "--mount",
+            "/assets=/srv/assets",
+        ])
+        .unwrap();
+        let mount = |path| {
+            let (root, rest) = config.mount_for(path);
+            (root.to_str().unwrap(), rest)
+        };
+
+        assert_eq!(mount("/a"), ("/srv/a", "/"));
+        assert_eq!(mount("/a/"), ("/srv/a", "/"));
+        assert_eq!(mount("/a/x.txt"), ("/srv/a", "/x.txt"));
+        assert_eq!(mount("/a/b"), ("/srv/ab", "/"));
+        assert_eq!(mount("/a/b/c/d.txt"), ("/srv/ab", "/c/d.txt"));
+        assert_eq!(mount("/a/bc.txt"), ("/srv/a", "/bc.txt"));
+        // Префикс совпадает только по границе сегмента
+        assert_eq!(mount("/ab"), ("/srv/root", "/ab"));
+        assert_eq!(mount("/assets2/x"), ("/srv/root", "/assets2/x"));
+        assert_eq!(mount("/assets/x"), ("/srv/assets", "/x"));
+        assert_eq!(mount("/"), ("/srv/root", "/"));
+    }
+
+    #[test]
+    fn port_flag_takes_precedence_over_port_env() {
+        let configure = |args: &[&str], port_env: Option<&str>| {
+            let args = [&["static-server"], args].concat();
+            let (mut config, matches) = ServerConfig::parse_matches(args).unwrap();
+            config
+                .apply_environment(&matches, port_env.map(OsString::from))
+                .map(|_| config)
+        };
+
+        assert_eq!(configure(&[], None).unwrap().port, 9898);
+        assert_eq!(configure(&[], Some("5000")).unwrap().port, 5000);
+        assert_eq!(
+            configure(&["--port", "8080"], Some("5000")).unwrap().port,
+            8080
+        );
+        assert_eq!(
+            configure(&["--port", "9898"], Some("5000")).unwrap().port,
+            9898
+        );
+        let range = configure(&["--port-range", "7000-7010"], Some("5000")).unwrap();
+        assert_eq!(range.ports(), 7000..=7010);
+        assert!(configure(&[], Some("http")).is_err());
+        assert!(configure(&["--port", "8080"], Some("http")).is_ok());
+
+        let paas = configure(&["--paas"], Some("5000")).unwrap();
+        assert_eq!((paas.host.as_str(), paas.port), ("0.0.0.0", 5000));
+        let host = configure(&["--paas", "--host", "127.0.0.1"], None).unwrap();
+        assert_eq!(host.host, "127.0.0.1");
+        assert_eq!(configure(&[], None).unwrap().host, "127.0.0.1");
+    }
 }
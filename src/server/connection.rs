@@ -1,29 +1,70 @@
+use std::collections::VecDeque;
 use std::fs::File;
 use std::net::TcpStream;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Instant;
+
+use log::info;
+
+use super::autoindex::Listing;
+use super::forwarded::Forwarded;
+use super::latency;
+use super::traffic::Category;
+use super::upload::Upload;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ConnectionStage {
     Recv,
+    RecvBody,
     Parse,
     SendHeaders,
     SendFile,
+    SendParts,
+    SendListing,
     Close,
 }
 
+#[derive(Debug)]
+pub enum ResponseSegment {
+    Bytes(Vec<u8>),
+    File { offset: u64, len: u64 },
+}
+
 #[derive(Debug)]
 pub struct Connection {
     pub fd: RawFd,
     pub stream: TcpStream,
     pub stage: ConnectionStage,
+    pub in_flight: bool,
     pub request_buffer: Vec<u8>,
     pub request_len: usize,
+    pub scan_offset: usize,
+    pub header_lines: usize,
+    pub header_end: Option<usize>,
     pub file: Option<File>,
     pub file_size: u64,
     pub file_sent: u64,
+    pub file_chunk: Vec<u8>,
+    pub file_chunk_sent: usize,
     pub headers: Vec<u8>,
     pub headers_sent: usize,
+    pub content_category: Category,
+    pub body_prefix: usize,
     pub is_head: bool,
+    pub segments: VecDeque<ResponseSegment>,
+    pub segment_sent: u64,
+    pub listing: Option<Listing>,
+    pub buffered_bytes: usize,
+    pub upload: Option<Upload>,
+    pub keep_alive: bool,
+    pub requests_served: usize,
+    pub created_at: Instant,
+    pub request_started: Instant,
+    pub last_activity: Instant,
+    pub request_line: String,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    pub forwarded: Option<Forwarded>,
 }
 
 impl Connection {
@@ -34,14 +75,118 @@ impl Connection {
             fd,
             stream,
             stage: ConnectionStage::Recv,
+            in_flight: false,
             request_buffer: vec![0u8; 8192],
             request_len: 0,
+            scan_offset: 0,
+            header_lines: 0,
+            header_end: None,
             file: None,
             file_size: 0,
             file_sent: 0,
+            file_chunk: Vec::new(),
+            file_chunk_sent: 0,
             headers: Vec::new(),
             headers_sent: 0,
+            content_category: Category::Other,
+            body_prefix: 0,
             is_head: false,
+            segments: VecDeque::new(),
+            segment_sent: 0,
+            listing: None,
+            buffered_bytes: 0,
+            upload: None,
+            keep_alive: false,
+            requests_served: 0,
+            created_at: Instant::now(),
+            request_started: Instant::now(),
+            last_activity: Instant::now(),
+            request_line: String::new(),
+            user_agent: None,
+            referer: None,
+            forwarded: None,
         }
     }
+
+    /// Строка access-лога в формате Combined Log Format
+    pub fn access_log_line(&self) -> String {
+        let forwarded_client = self
+            .forwarded
+            .as_ref()
+            .and_then(|forwarded| forwarded.client.clone());
+        let client = forwarded_client.unwrap_or_else(|| {
+            self.stream
+                .peer_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_else(|_| "-".to_string())
+        });
+
+        let status = String::from_utf8_lossy(&self.headers)
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or("-")
+            .to_string();
+
+        format!(
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"",
+            client,
+            chrono::Utc::now()
+                .with_timezone(&chrono_tz::Europe::Moscow)
+                .format("%d/%b/%Y:%H:%M:%S %z"),
+            self.request_line,
+            status,
+            self.headers_sent as u64 + self.file_sent,
+            self.referer.as_deref().unwrap_or("-"),
+            self.user_agent.as_deref().unwrap_or("-")
+        )
+    }
+
+    /// Состояние соединения для отладочной страницы в виде JSON-объекта
+    pub fn debug_json(&self) -> String {
+        let peer = self
+            .stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|_| "-".to_string());
+
+        format!(
+            "{{\"fd\":{},\"stage\":\"{:?}\",\"peer\":\"{}\",\"bytes_sent\":{},\"age_ms\":{}}}",
+            self.fd,
+            self.stage,
+            peer,
+            self.headers_sent as u64 + self.file_sent,
+            self.created_at.elapsed().as_millis()
+        )
+    }
+
+    pub fn finish_response(&mut self) {
+        info!("{}", self.access_log_line());
+        latency::record(self.request_started.elapsed());
+
+        if !self.keep_alive {
+            self.stage = ConnectionStage::Close;
+            return;
+        }
+
+        self.stage = ConnectionStage::Recv;
+        self.file = None;
+        self.file_size = 0;
+        self.file_sent = 0;
+        // Буфер порции не держим между запросами keep-alive
+        self.file_chunk = Vec::new();
+        self.file_chunk_sent = 0;
+        self.headers.clear();
+        self.headers_sent = 0;
+        self.body_prefix = 0;
+        self.is_head = false;
+        self.segments.clear();
+        self.segment_sent = 0;
+        self.listing = None;
+        self.keep_alive = false;
+        self.last_activity = Instant::now();
+        self.request_line.clear();
+        self.user_agent = None;
+        self.referer = None;
+        self.forwarded = None;
+    }
 }
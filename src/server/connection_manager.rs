@@ -1,15 +1,48 @@
 use std::collections::HashMap;
+use std::io::Write;
 use std::net::{TcpListener, TcpStream};
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, warn};
 
 use crate::server::config::ServerConfig;
 use crate::server::connection::{Connection, ConnectionStage};
+use crate::server::http_status::HttpStatus;
+use crate::server::trace;
 
 pub struct ConnectionManager {
     connections: Arc<Mutex<HashMap<RawFd, Connection>>>,
     pub listener: TcpListener,
     max_connections: usize,
+    fd_limit: usize,
+    fd_headroom: usize,
+    buffered_bytes: AtomicUsize,
+    max_response_buffer: usize,
+    rejections: Rejections,
+    peak_connections: AtomicUsize,
+    detached: AtomicUsize,
+}
+
+/// Причина, по которой принятое соединение было сразу закрыто
+#[derive(Debug, Clone, Copy)]
+pub enum Rejection {
+    /// Достигнут --max-connections
+    Capacity,
+    /// Дескриптор не меньше FD_SETSIZE и не может быть передан в pselect
+    Descriptor,
+    /// До RLIMIT_NOFILE осталось меньше --fd-headroom дескрипторов
+    Headroom,
+}
+
+/// Счётчики отклонённых соединений по причинам
+#[derive(Default)]
+struct Rejections {
+    capacity: AtomicU64,
+    descriptor: AtomicU64,
+    headroom: AtomicU64,
 }
 
 #[allow(dead_code)]
@@ -19,6 +52,13 @@ impl ConnectionManager {
             connections: Arc::new(Mutex::new(HashMap::new())),
             listener,
             max_connections: 1000,
+            fd_limit: usize::MAX,
+            fd_headroom: 0,
+            buffered_bytes: AtomicUsize::new(0),
+            max_response_buffer: 67108864,
+            rejections: Rejections::default(),
+            peak_connections: AtomicUsize::new(0),
+            detached: AtomicUsize::new(0),
         }
     }
 
@@ -27,23 +67,111 @@ impl ConnectionManager {
             connections: Arc::new(Mutex::new(HashMap::new())),
             listener,
             max_connections: config.max_connections,
+            fd_limit: open_files_limit().unwrap_or(usize::MAX),
+            fd_headroom: config.fd_headroom,
+            buffered_bytes: AtomicUsize::new(0),
+            max_response_buffer: config.max_response_buffer,
+            rejections: Rejections::default(),
+            peak_connections: AtomicUsize::new(0),
+            detached: AtomicUsize::new(0),
         }
     }
 
-    pub fn add_connection(&self, stream: TcpStream) -> bool {
+    pub fn add_connection(&self, mut stream: TcpStream) -> Result<(), Rejection> {
         let mut connections = self.connections.lock().unwrap();
-        if connections.len() >= self.max_connections {
-            return false;
+        let open = connections.len() + self.detached.load(Ordering::SeqCst);
+        if open >= self.max_connections {
+            self.record_rejection(Rejection::Capacity);
+            return Err(Rejection::Capacity);
+        }
+        // Отказываем заранее, чтобы accept не упёрся в EMFILE; клиенту
+        // отправляется 503, если сокет сразу его примет
+        if open.saturating_add(self.fd_headroom) >= self.fd_limit {
+            self.record_rejection(Rejection::Headroom);
+            let response = format!(
+                "{}Content-Length: 0\r\nRetry-After: 1\r\nConnection: close\r\n\r\n",
+                HttpStatus::ServiceUnavailable.as_response_line()
+            );
+            // Один неблокирующий write: недописанный ответ не дожидаемся
+            match stream.write(response.as_bytes()) {
+                Ok(written) if written < response.len() => warn!(
+                    "Short 503 write to rejected connection on fd {}: {} of {} bytes",
+                    stream.as_raw_fd(),
+                    written,
+                    response.len()
+                ),
+                Ok(_) => {}
+                Err(e) => warn!(
+                    "Failed to send 503 to rejected connection on fd {}: {}",
+                    stream.as_raw_fd(),
+                    e
+                ),
+            }
+            return Err(Rejection::Headroom);
         }
         let connection = Connection::new(stream);
         let fd = connection.fd;
         connections.insert(fd, connection);
-        true
+        self.peak_connections.fetch_max(open + 1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Мягкий RLIMIT_NOFILE, прочитанный при запуске (usize::MAX - без лимита)
+    pub fn fd_limit(&self) -> usize {
+        self.fd_limit
+    }
+
+    pub fn record_rejection(&self, reason: Rejection) {
+        let counter = match reason {
+            Rejection::Capacity => &self.rejections.capacity,
+            Rejection::Descriptor => &self.rejections.descriptor,
+            Rejection::Headroom => &self.rejections.headroom,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Наибольшее число одновременных соединений с момента запуска
+    pub fn peak_connections(&self) -> usize {
+        self.peak_connections.load(Ordering::Relaxed)
+    }
+
+    /// Счётчики отклонённых соединений для отладочной страницы
+    pub fn rejections_json(&self) -> String {
+        format!(
+            "{{\"capacity\":{},\"descriptor\":{},\"headroom\":{}}}",
+            self.rejections.capacity.load(Ordering::Relaxed),
+            self.rejections.descriptor.load(Ordering::Relaxed),
+            self.rejections.headroom.load(Ordering::Relaxed)
+        )
     }
 
     pub fn remove_connection(&self, fd: RawFd) -> Option<Connection> {
         let mut connections = self.connections.lock().unwrap();
-        connections.remove(&fd)
+        let conn = connections.remove(&fd);
+        trace::forget(fd);
+        if let Some(ref conn) = conn {
+            self.release_buffer(conn.buffered_bytes);
+        }
+        conn
+    }
+
+    /// Резервирует место под буферизованный ответ, если это укладывается
+    /// в общий бюджет --max-response-buffer
+    pub fn try_reserve_buffer(&self, len: usize) -> bool {
+        self.buffered_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                (used + len <= self.max_response_buffer).then_some(used + len)
+            })
+            .is_ok()
+    }
+
+    /// Учитывает буфер без проверки бюджета (заголовки потоковых ответов)
+    pub fn reserve_buffer(&self, len: usize) {
+        self.buffered_bytes.fetch_add(len, Ordering::SeqCst);
+    }
+
+    pub fn release_buffer(&self, len: usize) {
+        self.buffered_bytes.fetch_sub(len, Ordering::SeqCst);
     }
 
     pub fn with_connection<F, R>(&self, fd: RawFd, f: F) -> Option<R>
@@ -51,24 +179,71 @@ impl ConnectionManager {
         F: FnOnce(&mut Connection) -> R,
     {
         let mut connections = self.connections.lock().unwrap();
-        if let Some(conn) = connections.get_mut(&fd) {
-            Some(f(conn))
-        } else {
-            None
-        }
+        connections.get_mut(&fd).map(f)
+    }
+
+    /// Забирает соединение из таблицы на время `f`: обработчик читает файлы
+    /// и пишет в сокет без общей блокировки, и другие задачи пула тем временем
+    /// обслуживают остальные соединения. Пока соединение забрано, его не видят
+    /// pselect, обход по таймаутам и отладочная страница
+    pub fn with_detached_connection<F, R>(&self, fd: RawFd, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut Connection) -> R,
+    {
+        let mut conn = {
+            let mut connections = self.connections.lock().unwrap();
+            let conn = connections.remove(&fd)?;
+            self.detached.fetch_add(1, Ordering::SeqCst);
+            conn
+        };
+
+        let result = f(&mut conn);
+
+        let mut connections = self.connections.lock().unwrap();
+        connections.insert(fd, conn);
+        self.detached.fetch_sub(1, Ordering::SeqCst);
+        Some(result)
+    }
+
+    /// Доступ на чтение к соединениям в таблице под общей блокировкой
+    pub fn with_connections<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&HashMap<RawFd, Connection>) -> R,
+    {
+        f(&self.connections.lock().unwrap())
     }
 
     pub fn get_connections_for_select(&self) -> (Vec<RawFd>, Vec<RawFd>) {
-        let connections = self.connections.lock().unwrap();
+        let mut connections = self.connections.lock().unwrap();
         let mut read_fds = Vec::new();
         let mut write_fds = Vec::new();
 
-        for (fd, conn) in connections.iter() {
+        for (fd, conn) in connections.iter_mut() {
+            // Соединение с ещё не обработанной задачей в пуле не регистрируем
+            // повторно, иначе pselect будет сообщать о нём на каждой итерации
+            if conn.in_flight {
+                continue;
+            }
+
+            // FD_SET для дескриптора за пределами fd_set - неопределённое поведение
+            if *fd as usize >= libc::FD_SETSIZE && conn.stage != ConnectionStage::Close {
+                warn!(
+                    "Closing fd {}: beyond FD_SETSIZE {}, cannot be polled",
+                    fd,
+                    libc::FD_SETSIZE
+                );
+                conn.stage = ConnectionStage::Close;
+                continue;
+            }
+
             match conn.stage {
-                ConnectionStage::Recv | ConnectionStage::Parse => {
+                ConnectionStage::Recv | ConnectionStage::RecvBody | ConnectionStage::Parse => {
                     read_fds.push(*fd);
                 }
-                ConnectionStage::SendHeaders | ConnectionStage::SendFile => {
+                ConnectionStage::SendHeaders
+                | ConnectionStage::SendFile
+                | ConnectionStage::SendParts
+                | ConnectionStage::SendListing => {
                     write_fds.push(*fd);
                 }
                 ConnectionStage::Close => {}
@@ -78,6 +253,13 @@ impl ConnectionManager {
         (read_fds, write_fds)
     }
 
+    pub fn mark_in_flight(&self, fd: RawFd) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(conn) = connections.get_mut(&fd) {
+            conn.in_flight = true;
+        }
+    }
+
     pub fn get_closed_connections(&self) -> Vec<RawFd> {
         let connections = self.connections.lock().unwrap();
         connections
@@ -87,9 +269,71 @@ impl ConnectionManager {
             .collect()
     }
 
+    /// Keep-alive соединение ждёт следующего запроса: хотя бы один ответ
+    /// отправлен, а новый запрос ещё не начал приходить
+    pub fn is_idle_keepalive(conn: &Connection) -> bool {
+        conn.stage == ConnectionStage::Recv && conn.requests_served > 0 && conn.request_len == 0
+    }
+
+    /// Помечает на закрытие самое давнее простаивающее keep-alive соединение,
+    /// возвращая его дескриптор
+    pub fn close_oldest_idle(&self) -> Option<RawFd> {
+        let mut connections = self.connections.lock().unwrap();
+        let conn = connections
+            .values_mut()
+            .filter(|conn| !conn.in_flight && Self::is_idle_keepalive(conn))
+            .min_by_key(|conn| conn.last_activity)?;
+
+        conn.stage = ConnectionStage::Close;
+        Some(conn.fd)
+    }
+
+    /// Закрывает соединения, превысившие таймаут своей стадии: простой
+    /// keep-alive между запросами, получение запроса или отправку ответа.
+    /// Простаивающее keep-alive соединение старше max_age закрывается сразу
+    pub fn close_idle_connections(
+        &self,
+        keepalive_timeout: Duration,
+        recv_timeout: Duration,
+        send_timeout: Duration,
+        max_age: Option<Duration>,
+    ) {
+        let mut connections = self.connections.lock().unwrap();
+        for conn in connections.values_mut() {
+            if conn.in_flight {
+                continue;
+            }
+
+            let keepalive_idle = Self::is_idle_keepalive(conn);
+            if keepalive_idle && max_age.is_some_and(|age| conn.created_at.elapsed() > age) {
+                debug!("Closing idle fd {}: connection older than {:?}", conn.fd, max_age);
+                conn.stage = ConnectionStage::Close;
+                continue;
+            }
+
+            let timeout = match conn.stage {
+                ConnectionStage::Recv if keepalive_idle => keepalive_timeout,
+                ConnectionStage::Recv | ConnectionStage::RecvBody => recv_timeout,
+                ConnectionStage::SendHeaders
+                | ConnectionStage::SendFile
+                | ConnectionStage::SendParts
+                | ConnectionStage::SendListing => send_timeout,
+                ConnectionStage::Parse | ConnectionStage::Close => continue,
+            };
+
+            if conn.last_activity.elapsed() > timeout {
+                debug!(
+                    "Closing fd {}: no progress in {:?} for {:?}",
+                    conn.fd, conn.stage, timeout
+                );
+                conn.stage = ConnectionStage::Close;
+            }
+        }
+    }
+
     pub fn get_connections_count(&self) -> usize {
         let connections = self.connections.lock().unwrap();
-        connections.len()
+        connections.len() + self.detached.load(Ordering::SeqCst)
     }
 
     pub fn set_file_for_connection(
@@ -110,3 +354,70 @@ impl ConnectionManager {
         }
     }
 }
+
+/// Мягкий лимит открытых дескрипторов процесса
+fn open_files_limit() -> Option<usize> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } < 0 {
+        return None;
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    usize::try_from(limit.rlim_cur).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn detached_connection_does_not_block_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let _clients: Vec<TcpStream> = (0..2)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+        let manager = Arc::new(ConnectionManager::new(listener));
+        let fds: Vec<RawFd> = (0..2)
+            .map(|_| {
+                let (stream, _) = manager.listener.accept().unwrap();
+                let fd = stream.as_raw_fd();
+                manager.add_connection(stream).unwrap();
+                fd
+            })
+            .collect();
+
+        let (entered, wait_entered) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel::<()>();
+        let sender = {
+            let manager = Arc::clone(&manager);
+            let fd = fds[0];
+            thread::spawn(move || {
+                manager.with_detached_connection(fd, |_| {
+                    entered.send(()).unwrap();
+                    wait_release.recv().unwrap();
+                })
+            })
+        };
+        wait_entered.recv().unwrap();
+
+        // Пока первое соединение обрабатывается, второе доступно, а забранное
+        // по-прежнему учитывается в числе открытых
+        assert_eq!(
+            manager.with_connection(fds[1], |conn| conn.fd),
+            Some(fds[1])
+        );
+        assert_eq!(manager.with_connections(HashMap::len), 1);
+        assert_eq!(manager.get_connections_count(), 2);
+
+        release.send(()).unwrap();
+        sender.join().unwrap();
+        assert_eq!(manager.with_connections(HashMap::len), 2);
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+use std::io;
+
+/// Ошибки запуска сервера с контекстом и подсказкой для оператора
+#[derive(Debug)]
+pub enum ServerError {
+    /// Не удалось занять адрес (`addr` - `host:port` или `host:first-last`)
+    Bind { addr: String, source: io::Error },
+    Io(io::Error),
+}
+
+impl ServerError {
+    /// Адрес занят другим сокетом - такую ошибку имеет смысл повторить
+    /// (например, пока старый процесс не освободил порт)
+    pub fn is_addr_in_use(&self) -> bool {
+        matches!(self, ServerError::Bind { source, .. } if source.kind() == io::ErrorKind::AddrInUse)
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        let ServerError::Bind { source, .. } = self else {
+            return None;
+        };
+
+        match source.kind() {
+            io::ErrorKind::AddrInUse => Some(
+                "another process is probably listening there: stop it, choose another \
+                 --port or --port-range, or wait out TIME_WAIT with --bind-retries",
+            ),
+            io::ErrorKind::PermissionDenied => {
+                Some("ports below 1024 require root or CAP_NET_BIND_SERVICE")
+            }
+            io::ErrorKind::AddrNotAvailable => {
+                Some("--host must be an address assigned to this machine")
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Bind { addr, source } => {
+                write!(f, "cannot listen on {}: {}", addr, source)?;
+                if let Some(hint) = self.hint() {
+                    write!(f, " ({})", hint)?;
+                }
+                Ok(())
+            }
+            ServerError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Bind { source, .. } => Some(source),
+            ServerError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        ServerError::Io(e)
+    }
+}
@@ -0,0 +1,138 @@
+use super::request::HttpRequest;
+
+/// Сведения о клиенте, переданные доверенным прокси
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Forwarded {
+    pub client: Option<String>,
+    pub proto: Option<String>,
+    pub host: Option<String>,
+}
+
+impl Forwarded {
+    /// Разбирает `Forwarded` (RFC 7239), а при его отсутствии -
+    /// `X-Forwarded-For`, `X-Forwarded-Proto` и `X-Forwarded-Host`
+    pub fn from_request(request: &HttpRequest) -> Option<Self> {
+        if let Some(value) = request.header("Forwarded") {
+            return Some(parse_forwarded(value));
+        }
+
+        let client = request
+            .header("X-Forwarded-For")
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+            .filter(|client| !client.is_empty())
+            .map(str::to_string);
+        let proto = request.header("X-Forwarded-Proto").map(str::to_string);
+        let host = request.header("X-Forwarded-Host").map(str::to_string);
+
+        if client.is_none() && proto.is_none() && host.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            client,
+            proto,
+            host,
+        })
+    }
+}
+
+/// Берётся первый элемент списка: он добавлен ближайшим к клиенту прокси
+fn parse_forwarded(value: &str) -> Forwarded {
+    let mut forwarded = Forwarded::default();
+    let first = value.split(',').next().unwrap_or_default();
+
+    for pair in first.split(';') {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+
+        match name.trim().to_ascii_lowercase().as_str() {
+            "for" => forwarded.client = Some(strip_port(value).to_string()),
+            "proto" => forwarded.proto = Some(value.to_ascii_lowercase()),
+            "host" => forwarded.host = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    forwarded
+}
+
+/// `[2001:db8::1]:4711` -> `2001:db8::1`, `192.0.2.60:8080` -> `192.0.2.60`
+fn strip_port(node: &str) -> &str {
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+
+    match node.split_once(':') {
+        Some((ip, _)) if !ip.contains(':') => ip,
+        _ => node,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            target: "/".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn forwarded_with_quoted_ipv6() {
+        let forwarded =
+            parse_forwarded("for=\"[2001:db8:cafe::17]:4711\";proto=HTTPS;host=example.com");
+        assert_eq!(
+            forwarded,
+            Forwarded {
+                client: Some("2001:db8:cafe::17".to_string()),
+                proto: Some("https".to_string()),
+                host: Some("example.com".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn forwarded_for_token_uses_first_element() {
+        let forwarded = parse_forwarded("For=192.0.2.60:8080, for=198.51.100.17");
+        assert_eq!(forwarded.client.as_deref(), Some("192.0.2.60"));
+        assert_eq!(forwarded.proto, None);
+        assert_eq!(
+            parse_forwarded("for=unknown").client.as_deref(),
+            Some("unknown")
+        );
+    }
+
+    #[test]
+    fn forwarded_takes_precedence_over_x_forwarded() {
+        let forwarded = Forwarded::from_request(&request(&[
+            ("X-Forwarded-For", "203.0.113.1"),
+            ("Forwarded", "for=192.0.2.43"),
+        ]))
+        .unwrap();
+        assert_eq!(forwarded.client.as_deref(), Some("192.0.2.43"));
+    }
+
+    #[test]
+    fn x_forwarded_headers() {
+        let forwarded = Forwarded::from_request(&request(&[
+            ("X-Forwarded-For", " 203.0.113.1 , 10.0.0.1"),
+            ("X-Forwarded-Proto", "https"),
+        ]))
+        .unwrap();
+        assert_eq!(forwarded.client.as_deref(), Some("203.0.113.1"));
+        assert_eq!(forwarded.proto.as_deref(), Some("https"));
+        assert_eq!(forwarded.host, None);
+
+        assert_eq!(Forwarded::from_request(&request(&[])), None);
+    }
+}
@@ -1,16 +1,74 @@
-use std::sync::Arc;
-use std::io::{Read, Seek, Write};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use log::{debug, error, info, warn};
 
+use super::autoindex::{self, Listing};
+use super::compression::{
+    accepts_gzip, accepts_identity, gzip_file, is_compressible, MAX_COMPRESS_SIZE, MIN_COMPRESS_SIZE,
+};
+use super::config::{EtagMode, RobotsPolicy, RootResponse, ServerConfig};
+use crate::static_files::robots_content;
 use super::http_status::HttpStatus;
-use super::connection::ConnectionStage;
+use super::latency;
+use super::connection::{Connection, ConnectionStage, ResponseSegment};
 use super::connection_manager::ConnectionManager;
+use super::forwarded::Forwarded;
+use super::range::{parse_range, RangeRequest};
+use super::request::{HttpRequest, RequestTarget, normalize_path, weighted_items};
+use super::trace::{self, Direction};
+use super::traffic::{self, Category};
+use super::upload::Upload;
+use super::validators::Validators;
+use super::worker_pool::WorkerPool;
+
+/// Сколько байт (заголовки + начало тела) можно отправить одним write
+const COALESCE_LIMIT: usize = 65536;
+
+/// Размер порции файла, читаемой для отправки
+const FILE_CHUNK_SIZE: usize = 65536;
+
+/// Сколько символов необработанной строки запроса попадает в журнал
+const MAX_LOGGED_REQUEST_LINE: usize = 256;
+
+/// Корни (--document-root и --mount), пропавшие во время работы (удалены
+/// или отмонтированы)
+static MISSING_ROOTS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+struct PreparedResponse {
+    headers: Vec<u8>,
+    file: Option<std::fs::File>,
+    file_size: u64,
+    is_head: bool,
+    segments: VecDeque<ResponseSegment>,
+    listing: Option<Listing>,
+    keep_alive: bool,
+    upload: Option<Upload>,
+    /// Часть бюджета --max-response-buffer, занятая ещё при подготовке ответа
+    reserved: usize,
+}
+
+impl PreparedResponse {
+    fn buffered_len(&self) -> usize {
+        let segments: usize = self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                ResponseSegment::Bytes(data) => data.len(),
+                ResponseSegment::File { .. } => 0,
+            })
+            .sum();
+
+        self.headers.len() + segments
+    }
+}
 
 pub fn handle_readable_in_pool(
     fd: i32,
     connection_manager: Arc<ConnectionManager>,
-    doc_root: std::path::PathBuf,
-    max_file_size: u64,
+    config: Arc<ServerConfig>,
+    worker_pool: Arc<WorkerPool>,
 ) {
     debug!(
         "[Thread {:?}] Handling readable connection fd {}",
@@ -18,9 +76,16 @@ pub fn handle_readable_in_pool(
         fd
     );
 
-    connection_manager.with_connection(fd, |conn| {
-        if conn.stage != ConnectionStage::Recv {
-            return;
+    connection_manager.with_detached_connection(fd, |conn| {
+        conn.in_flight = false;
+
+        match conn.stage {
+            ConnectionStage::Recv => {}
+            ConnectionStage::RecvBody => {
+                receive_body(fd, conn);
+                return;
+            }
+            _ => return,
         }
 
         let bytes_read = match conn.stream.read(&mut conn.request_buffer[conn.request_len..]) {
@@ -31,11 +96,21 @@ pub fn handle_readable_in_pool(
             }
             Ok(n) => {
                 debug!("Read {} bytes from fd {}", n, fd);
+                trace::record(
+                    fd,
+                    Direction::Recv,
+                    &conn.request_buffer[conn.request_len..conn.request_len + n],
+                );
                 n
             }
             Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                 return;
             }
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                warn!("Receive timeout (SO_RCVTIMEO) on fd {}, closing", fd);
+                conn.stage = ConnectionStage::Close;
+                return;
+            }
             Err(e) => {
                 error!("Error reading from connection {}: {}", fd, e);
                 conn.stage = ConnectionStage::Close;
@@ -43,263 +118,2411 @@ pub fn handle_readable_in_pool(
             }
         };
 
+        if conn.request_len == 0 {
+            conn.request_started = std::time::Instant::now();
+        }
         conn.request_len += bytes_read;
+        conn.last_activity = std::time::Instant::now();
 
-        let buffer_slice = &conn.request_buffer[..conn.request_len];
-        if contains_double_newline(buffer_slice) {
-            debug!(
-                "Full request received on fd {} ({} bytes)",
-                fd, conn.request_len
-            );
+        process_request_buffer(fd, conn, &config, &worker_pool, &connection_manager);
+    });
+}
 
-            let request_data = buffer_slice[..conn.request_len].to_vec();
-            let request_str = String::from_utf8_lossy(&request_data);
+/// Ищет в буфере соединения конец заголовков и, если запрос получен целиком,
+/// разбирает его и готовит ответ. Байты после запроса (следующий запрос
+/// конвейера) остаются в начале буфера
+fn process_request_buffer(
+    fd: i32,
+    conn: &mut Connection,
+    config: &ServerConfig,
+    worker_pool: &WorkerPool,
+    connection_manager: &ConnectionManager,
+) {
+    let header_end = conn.header_end.or_else(|| scan_header_end(conn));
 
+    // Незавершённый заголовок из множества коротких строк не должен
+    // держать соединение в Recv: строк больше, чем строка запроса и
+    // --max-headers заголовков, быть не может. Заполненный буфер без
+    // разделителя тоже означает 431: следующее чтение пришлось бы делать
+    // в пустой срез, и его 0 выглядел бы как закрытие соединения клиентом
+    if header_end.is_none() {
+        let buffer_full = conn.request_len >= conn.request_buffer.len();
+        if conn.header_lines > config.max_headers + 1 || buffer_full {
+            if buffer_full {
+                warn!(
+                    "Header section on fd {} does not fit the request buffer ({} bytes)",
+                    fd,
+                    conn.request_buffer.len()
+                );
+            } else {
+                warn!(
+                    "Unterminated header section on fd {} exceeds {} lines",
+                    fd, config.max_headers
+                );
+            }
+            let request_line = raw_request_line(&conn.request_buffer[..conn.request_len]);
             conn.request_len = 0;
-            conn.stage = ConnectionStage::Parse;
+            conn.scan_offset = 0;
+            conn.header_lines = 0;
+            conn.requests_served += 1;
+            conn.headers =
+                format_error_response(HttpStatus::RequestHeaderFieldsTooLarge, None);
+            log_error_response(&request_line, &conn.headers, fd);
+            conn.headers_sent = 0;
+            conn.keep_alive = false;
+            conn.stage = ConnectionStage::SendHeaders;
+            return;
+        }
+    }
 
-            match parse_http_request(
-                &request_str,
-                &doc_root,
-                max_file_size,
+    if let Some(header_end) = header_end {
+        // Заголовки получены; тело, кроме загрузки PUT, дочитываем
+        // целиком, прежде чем разбирать запрос
+        let body_len = buffered_body_len(&conn.request_buffer[..header_end], config);
+        if header_end + body_len > conn.request_buffer.len() {
+            warn!(
+                "Request body on fd {} does not fit the request buffer ({} bytes)",
+                fd, body_len
+            );
+            let request_line = raw_request_line(&conn.request_buffer[..header_end]);
+            conn.request_len = 0;
+            conn.scan_offset = 0;
+            conn.header_lines = 0;
+            conn.header_end = None;
+            conn.requests_served += 1;
+            conn.headers = format_error_response(HttpStatus::PayloadTooLarge, None);
+            log_error_response(&request_line, &conn.headers, fd);
+            conn.headers_sent = 0;
+            conn.keep_alive = false;
+            conn.stage = ConnectionStage::SendHeaders;
+            return;
+        }
+        if conn.request_len < header_end + body_len {
+            debug!(
+                "Headers received on fd {}, waiting for {} body bytes",
                 fd,
-            ) {
-                Ok((headers, file, file_size, is_head)) => {
-                    conn.headers = headers;
-                    conn.headers_sent = 0;
-                    conn.file = file;
-                    conn.file_size = file_size;
-                    conn.is_head = is_head;
+                header_end + body_len - conn.request_len
+            );
+            conn.header_end = Some(header_end);
+            return;
+        }
+
+        debug!(
+            "Full request received on fd {} ({} bytes)",
+            fd, conn.request_len
+        );
+
+        let request_data = conn.request_buffer[..header_end].to_vec();
+        let request_str = String::from_utf8_lossy(&request_data);
+
+        // Байты после тела - начало следующего запроса конвейера: переносим
+        // их в начало буфера, конец его заголовков ищется заново
+        let request_end = header_end + body_len;
+        conn.request_buffer.copy_within(request_end..conn.request_len, 0);
+        conn.request_len -= request_end;
+        conn.scan_offset = 0;
+        conn.header_lines = 0;
+        conn.header_end = None;
+        conn.stage = ConnectionStage::Parse;
+
+        conn.requests_served += 1;
+
+        let mut is_head = false;
+        let mut expects_continue = false;
+        let parsed = match request_line_utf8_error(&request_data) {
+            Some(offset) => {
+                warn!(
+                    "Request line on fd {} is not valid UTF-8 (invalid byte at offset {})",
+                    fd, offset
+                );
+                Err(HttpStatus::BadRequest)
+            }
+            None => {
+                let parse_started = std::time::Instant::now();
+                let parsed = HttpRequest::parse(&request_str, config);
+                debug!(
+                    "Parsed {} byte request on fd {} in {:?}",
+                    request_data.len(),
+                    fd,
+                    parse_started.elapsed()
+                );
+                parsed
+            }
+        };
+
+        let response = match parsed {
+            Ok(request) => {
+                is_head = request.method == "HEAD";
+                expects_continue = request.expects_continue();
+                conn.request_line =
+                    format!("{} {} {}", request.method, request.target, request.version);
+                conn.user_agent = request.header("User-Agent").map(str::to_string);
+                conn.referer = request.header("Referer").map(str::to_string);
+                conn.forwarded = config
+                    .trust_proxy
+                    .then(|| Forwarded::from_request(&request))
+                    .flatten();
+
+                debug!(
+                    "Request on fd {}: \"{}\" (User-Agent: {}, Referer: {})",
+                    fd,
+                    conn.request_line,
+                    conn.user_agent.as_deref().unwrap_or("-"),
+                    conn.referer.as_deref().unwrap_or("-")
+                );
+                if let Some(ref forwarded) = conn.forwarded {
+                    debug!(
+                        "Forwarded on fd {}: for={} proto={} host={}",
+                        fd,
+                        forwarded.client.as_deref().unwrap_or("-"),
+                        forwarded.proto.as_deref().unwrap_or("-"),
+                        forwarded.host.as_deref().unwrap_or("-")
+                    );
+                }
+
+                if is_admin_shutdown_request(&request, config) {
+                    admin_shutdown_response(&request, config, fd)
+                } else if is_debug_request(&request, config) {
+                    if config.admin_auth.is_some() && !is_admin_authorized(&request, config) {
+                        warn!("Unauthorized debug page request on fd {}", fd);
+                        Err(admin_unauthorized(&request))
+                    } else {
+                        Ok(connection_manager.with_connections(|peers| {
+                            debug_connections_response(
+                                &request,
+                                config,
+                                conn,
+                                peers,
+                                worker_pool,
+                                connection_manager,
+                            )
+                        }))
+                    }
+                } else {
+                    // Соединение старше --max-connection-age считается
+                    // исчерпавшим keep-alive: ответ уйдёт с Connection: close
+                    let aged = config.max_connection_age.is_some_and(|age| {
+                        conn.created_at.elapsed().as_secs() >= age
+                    });
+                    let requests_served = if aged {
+                        debug!("Connection on fd {} reached --max-connection-age", fd);
+                        config.keepalive_max
+                    } else {
+                        conn.requests_served
+                    };
+                    parse_http_request(
+                        &request,
+                        config,
+                        requests_served,
+                        fd,
+                        Some(connection_manager),
+                    )
+                }
+            }
+            Err(status) => {
+                warn!("Malformed request on fd {}: {}", fd, status.code());
+                Err(format_error_response(status, None))
+            }
+        };
+
+        let response = match response {
+            Ok(response) => {
+                let buffered_len = response.buffered_len();
+                if response.file.is_some() || response.reserved > 0 {
+                    // Резерв под сжатие заменяется фактическим размером ответа
+                    connection_manager.reserve_buffer(buffered_len);
+                    connection_manager.release_buffer(response.reserved);
+                    Ok((response, buffered_len))
+                } else if connection_manager.try_reserve_buffer(buffered_len) {
+                    Ok((response, buffered_len))
+                } else {
+                    warn!(
+                        "Response buffer budget exhausted, refusing {} byte response on fd {}",
+                        buffered_len, fd
+                    );
+                    Err(format_error_response(HttpStatus::ServiceUnavailable, None))
+                }
+            }
+            Err(error_headers) => Err(error_headers),
+        };
+
+        match response {
+            Ok((response, buffered_len)) => {
+                conn.buffered_bytes = buffered_len;
+                conn.headers = response.headers;
+                conn.headers_sent = 0;
+                conn.file = response.file;
+                conn.file_size = response.file_size;
+                conn.is_head = response.is_head;
+                conn.segments = response.segments;
+                conn.segment_sent = 0;
+                conn.listing = response.listing;
+                conn.keep_alive = response.keep_alive;
+                conn.upload = response.upload;
+
+                if conn.upload.is_some() {
+                    // Тело загрузки в буфер не учитывалось (body_len = 0):
+                    // всё, что пришло после заголовков, - его начало
+                    let body_data = conn.request_buffer[..conn.request_len].to_vec();
+                    conn.request_len = 0;
+                    conn.stage = ConnectionStage::RecvBody;
+                    debug!("Receiving request body on fd {}", fd);
+                    if expects_continue && body_data.is_empty() {
+                        send_continue(fd, conn);
+                    }
+                    if conn.upload.is_some() {
+                        store_body(fd, conn, &body_data);
+                    }
+                } else {
+                    let prefix = coalesce_body_prefix(fd, conn);
+                    connection_manager.reserve_buffer(prefix);
+                    conn.buffered_bytes += prefix;
                     conn.stage = ConnectionStage::SendHeaders;
-                    
                     debug!("Request parsed and ready to send headers on fd {}", fd);
                 }
-                Err(error_headers) => {
-                    conn.headers = error_headers;
-                    conn.headers_sent = 0;
-                    conn.stage = ConnectionStage::SendHeaders;
-                    debug!("Error response ready to send on fd {}", fd);
+            }
+            Err(mut error_headers) => {
+                if is_head {
+                    strip_response_body(&mut error_headers);
                 }
+                conn.headers = error_headers;
+                // Запрос мог не разобраться: тогда в журнал идёт сырая строка
+                let request_line = if conn.request_line.is_empty() {
+                    raw_request_line(&request_data)
+                } else {
+                    conn.request_line.clone()
+                };
+                log_error_response(&request_line, &conn.headers, fd);
+                conn.headers_sent = 0;
+                conn.keep_alive = false;
+                conn.stage = ConnectionStage::SendHeaders;
+                debug!("Error response ready to send on fd {}", fd);
             }
         }
-    });
+    }
+}
+
+/// Строка журнала на каждый ответ с ошибкой: метод, цель запроса и статус,
+/// по которым подробности выше в журнале (путь файла, причина) связываются
+/// с запросом клиента
+fn log_error_response(request_line: &str, response: &[u8], fd: i32) {
+    let status = response
+        .split(|&byte| byte == b' ')
+        .nth(1)
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+
+    if status.starts_with('5') {
+        error!("\"{}\" {} on fd {}", request_line, status, fd);
+    } else {
+        info!("\"{}\" {} on fd {}", request_line, status, fd);
+    }
+}
+
+/// Первая строка необработанного запроса для журнала, не длиннее
+/// `MAX_LOGGED_REQUEST_LINE` символов
+fn raw_request_line(request: &[u8]) -> String {
+    let end = request
+        .iter()
+        .position(|&byte| byte == b'\r' || byte == b'\n')
+        .unwrap_or(request.len());
+    String::from_utf8_lossy(&request[..end])
+        .chars()
+        .take(MAX_LOGGED_REQUEST_LINE)
+        .collect()
+}
+
+fn is_debug_request(request: &HttpRequest, config: &ServerConfig) -> bool {
+    matches!(request.method.as_str(), "GET" | "HEAD")
+        && matches!(
+            (request.target_form(), config.debug_path.as_deref()),
+            (RequestTarget::Origin(path), Some(debug_path)) if path == debug_path
+        )
+}
+
+fn is_admin_shutdown_request(request: &HttpRequest, config: &ServerConfig) -> bool {
+    matches!(
+        (request.target_form(), config.admin_shutdown_path.as_deref()),
+        (RequestTarget::Origin(path), Some(admin_path)) if path == admin_path
+    )
+}
+
+/// Basic-авторизация запроса учётными данными --admin-auth; токен
+/// сравнивается за постоянное время
+fn is_admin_authorized(request: &HttpRequest, config: &ServerConfig) -> bool {
+    match (request.header("Authorization"), config.admin_auth.as_deref()) {
+        (Some(header), Some(credentials)) => header.split_once(' ').is_some_and(|(scheme, token)| {
+            scheme.eq_ignore_ascii_case("Basic")
+                && constant_time_eq(
+                    token.trim().as_bytes(),
+                    base64_encode(credentials.as_bytes()).as_bytes(),
+                )
+        }),
+        _ => false,
+    }
+}
+
+/// 401 с приглашением к Basic-авторизации для административных путей
+fn admin_unauthorized(request: &HttpRequest) -> Vec<u8> {
+    let status = HttpStatus::Unauthorized;
+    let (content_type, body) = error_body(status, Some(request));
+    format_error_response_with(
+        status,
+        "WWW-Authenticate: Basic realm=\"admin\"\r\n",
+        content_type,
+        &body,
+    )
+}
+
+/// POST на --admin-shutdown-path с верными --admin-auth запускает плавную
+/// остановку: новые соединения больше не принимаются, текущие дообслуживаются
+fn admin_shutdown_response(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    fd: i32,
+) -> Result<PreparedResponse, Vec<u8>> {
+    if request.method != "POST" {
+        return Err(format_method_not_allowed(request, "POST"));
+    }
+
+    if !is_admin_authorized(request, config) {
+        warn!("Unauthorized shutdown request on fd {}", fd);
+        return Err(admin_unauthorized(request));
+    }
+
+    warn!("Shutdown requested via {} on fd {}", request.target, fd);
+    super::request_shutdown();
+
+    Ok(PreparedResponse {
+        headers: format!(
+            "{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            HttpStatus::Accepted.as_response_line()
+        )
+        .into_bytes(),
+        file: None,
+        file_size: 0,
+        is_head: false,
+        segments: VecDeque::new(),
+        listing: None,
+        keep_alive: false,
+        upload: None,
+        reserved: 0,
+    })
+}
+
+/// Сравнение за время, не зависящее от позиции первого несовпадения,
+/// чтобы по времени ответа нельзя было подбирать --admin-auth побайтно
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// JSON со списком активных соединений для --debug-path
+fn debug_connections_response(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    conn: &Connection,
+    peers: &HashMap<i32, Connection>,
+    worker_pool: &WorkerPool,
+    connection_manager: &ConnectionManager,
+) -> PreparedResponse {
+    let mut entries: Vec<&Connection> = peers.values().chain(std::iter::once(conn)).collect();
+    entries.sort_by_key(|entry| entry.fd);
+
+    let latency = latency::percentiles()
+        .map(|percentiles| percentiles.to_json())
+        .unwrap_or_else(|| "null".to_string());
+
+    let body = format!(
+        "{{\"connections\":[{}],\"http_connections_peak\":{},\"http_response_bytes_total\":{},\"latency\":{},\"limits\":{},\"pool\":{},\"rejected\":{}}}\n",
+        entries
+            .iter()
+            .map(|entry| entry.debug_json())
+            .collect::<Vec<_>>()
+            .join(","),
+        connection_manager.peak_connections(),
+        traffic::to_json(),
+        latency,
+        config.limits_json(),
+        worker_pool.stats_json(),
+        connection_manager.rejections_json()
+    );
+
+    generated_response(
+        request,
+        config,
+        conn.requests_served,
+        "application/json",
+        body.into_bytes(),
+    )
+}
+
+fn receive_body(fd: i32, conn: &mut Connection) {
+    let remaining = conn.upload.as_ref().map(Upload::remaining).unwrap_or_default();
+    let chunk_len = remaining.min(conn.request_buffer.len() as u64) as usize;
+
+    match conn.stream.read(&mut conn.request_buffer[..chunk_len]) {
+        Ok(0) => {
+            warn!("Connection closed during upload on fd {}", fd);
+            conn.upload = None;
+            conn.stage = ConnectionStage::Close;
+        }
+        Ok(n) => {
+            conn.last_activity = std::time::Instant::now();
+            trace::record(fd, Direction::Recv, &conn.request_buffer[..n]);
+            let data = conn.request_buffer[..n].to_vec();
+            store_body(fd, conn, &data);
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+        Err(e) => {
+            error!("Error reading request body on fd {}: {}", fd, e);
+            conn.upload = None;
+            conn.stage = ConnectionStage::Close;
+        }
+    }
+}
+
+/// Промежуточный ответ на `Expect: 100-continue`: загрузка принята, клиент
+/// может слать тело. Отказ (413, 411 и т. п.) уходит окончательным ответом
+/// без него. Если сокет не принял ответ сразу, клиент всё равно начнёт
+/// передачу по своему таймауту ожидания
+fn send_continue(fd: i32, conn: &mut Connection) {
+    const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+    match conn.stream.write(CONTINUE) {
+        Ok(n) => {
+            trace::record(fd, Direction::Send, &CONTINUE[..n]);
+            if n < CONTINUE.len() {
+                // Остаток строки состояния смешался бы с окончательным ответом
+                warn!("100 Continue partially sent on fd {}, closing", fd);
+                conn.upload = None;
+                conn.stage = ConnectionStage::Close;
+            } else {
+                debug!("Sent 100 Continue on fd {}", fd);
+            }
+        }
+        Err(e) => warn!("Failed to send 100 Continue on fd {}: {}", fd, e),
+    }
+}
+
+/// Записывает очередную порцию тела в загрузку и по её завершении
+/// атомарно переносит файл на место и переходит к отправке ответа
+fn store_body(fd: i32, conn: &mut Connection, data: &[u8]) {
+    let upload = match conn.upload.as_mut() {
+        Some(upload) => upload,
+        None => return,
+    };
+
+    let len = (upload.remaining().min(data.len() as u64)) as usize;
+    if let Err(e) = upload.write(&data[..len]) {
+        error!("Error writing upload on fd {}: {}", fd, e);
+        fail_upload(fd, conn);
+        return;
+    }
+
+    if !upload.is_complete() {
+        return;
+    }
+
+    let upload = conn.upload.take().unwrap();
+    let received = upload.received;
+    match upload.commit() {
+        Ok(()) => {
+            info!("Upload completed on fd {} ({} bytes)", fd, received);
+            conn.stage = ConnectionStage::SendHeaders;
+        }
+        Err(e) => {
+            error!("Error committing upload on fd {}: {}", fd, e);
+            fail_upload(fd, conn);
+        }
+    }
+}
+
+fn fail_upload(fd: i32, conn: &mut Connection) {
+    conn.upload = None;
+    conn.headers = format_error_response(HttpStatus::InternalServerError, None);
+    log_error_response(&conn.request_line, &conn.headers, fd);
+    conn.headers_sent = 0;
+    conn.keep_alive = false;
+    conn.stage = ConnectionStage::SendHeaders;
 }
 
-pub fn handle_writable_in_pool(fd: i32, connection_manager: Arc<ConnectionManager>) {
+pub fn handle_writable_in_pool(
+    fd: i32,
+    connection_manager: Arc<ConnectionManager>,
+    config: Arc<ServerConfig>,
+    worker_pool: Arc<WorkerPool>,
+) {
     debug!(
         "[Thread {:?}] Handling writable connection fd {}",
         std::thread::current().id(),
         fd
     );
 
-    connection_manager.with_connection(fd, |conn| {
+    connection_manager.with_detached_connection(fd, |conn| {
+        conn.in_flight = false;
+        let sent_before = conn.headers_sent as u64 + conn.file_sent;
+
+        if conn.stage == ConnectionStage::SendHeaders && conn.headers_sent == 0 {
+            conn.content_category = Category::from_response(&conn.headers);
+        }
+
         match conn.stage {
-            ConnectionStage::SendHeaders => {
-                if conn.headers_sent < conn.headers.len() {
-                    match conn.stream.write(&conn.headers[conn.headers_sent..]) {
-                        Ok(0) => {
-                            debug!("Connection closed while sending headers on fd {}", fd);
-                            conn.stage = ConnectionStage::Close;
-                            return;
-                        }
-                        Ok(n) => {
-                            debug!("Sent {} header bytes on fd {}", n, fd);
-                            conn.headers_sent += n;
-                            if conn.headers_sent >= conn.headers.len() {
-                                if conn.is_head || conn.file.is_none() {
-                                    info!("Headers sent for HEAD request on fd {}", fd);
-                                    conn.stage = ConnectionStage::Close;
-                                } else {
-                                    debug!("Headers sent, starting file transfer on fd {}", fd);
-                                    conn.stage = ConnectionStage::SendFile;
-                                }
-                            }
-                        }
-                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                            return;
-                        }
-                        Err(e) => {
-                            error!("Error writing headers to fd {}: {}", fd, e);
-                            conn.stage = ConnectionStage::Close;
-                        }
+            ConnectionStage::SendHeaders if conn.headers_sent < conn.headers.len() => {
+                match conn.stream.write(&conn.headers[conn.headers_sent..]) {
+                    Ok(0) => {
+                        debug!("Connection closed while sending headers on fd {}", fd);
+                        conn.stage = ConnectionStage::Close;
                     }
-                }
-            }
+                    Ok(n) => {
+                        debug!("Sent {} header bytes on fd {}", n, fd);
+                        trace::record(
+                            fd,
+                            Direction::Send,
+                            &conn.headers[conn.headers_sent..conn.headers_sent + n],
+                        );
+                        traffic::record(conn.content_category, n);
+                        conn.headers_sent += n;
+                        if conn.headers_sent >= conn.headers.len() {
+                            // Начало тела, отправленное вместе с заголовками,
+                            // учитываем как байты файла
+                            conn.headers_sent -= conn.body_prefix;
+                            conn.file_sent += conn.body_prefix as u64;
 
-            ConnectionStage::SendFile => {
-                if let Some(ref mut file) = conn.file {
-                    let mut buffer = [0u8; 65536];
-                    match file.read(&mut buffer) {
-                        Ok(0) => {
-                            info!(
-                                "File sent completely on fd {} ({} bytes)",
-                                fd, conn.file_sent
-                            );
-                            conn.stage = ConnectionStage::Close;
-                        }
-                        Ok(bytes_read) => match conn.stream.write(&buffer[..bytes_read]) {
-                            Ok(0) => {
-                                debug!("Connection closed while sending file on fd {}", fd);
-                                conn.stage = ConnectionStage::Close;
-                            }
-                            Ok(bytes_written) => {
-                                conn.file_sent += bytes_written as u64;
-                                debug!(
-                                    "Sent {} file bytes on fd {} (total: {}/{})",
-                                    bytes_written, fd, conn.file_sent, conn.file_size
+                            if !conn.is_head && conn.listing.is_some() {
+                                debug!("Headers sent, streaming directory listing on fd {}", fd);
+                                conn.stage = ConnectionStage::SendListing;
+                            } else if conn.is_head || conn.file.is_none() {
+                                info!("Headers sent for HEAD request on fd {}", fd);
+                                conn.finish_response();
+                            } else if !conn.segments.is_empty() {
+                                debug!("Headers sent, starting multipart transfer on fd {}", fd);
+                                conn.stage = ConnectionStage::SendParts;
+                            } else if conn.file_sent >= conn.file_size {
+                                info!(
+                                    "File sent together with headers on fd {} ({} bytes)",
+                                    fd, conn.file_sent
                                 );
-
-                                if conn.file_sent >= conn.file_size {
-                                    info!(
-                                        "File sent completely on fd {} ({} bytes)",
-                                        fd, conn.file_sent
-                                    );
-                                    conn.stage = ConnectionStage::Close;
-                                } else if bytes_written < bytes_read {
-                                    file.seek(std::io::SeekFrom::Current(
-                                        -(bytes_read as i64 - bytes_written as i64),
-                                    ))
-                                    .ok();
-                                }
-                            }
-                            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                                file.seek(std::io::SeekFrom::Current(-(bytes_read as i64)))
-                                    .ok();
+                                conn.finish_response();
+                            } else {
+                                debug!("Headers sent, starting file transfer on fd {}", fd);
+                                conn.stage = ConnectionStage::SendFile;
                             }
-                            Err(e) => {
-                                error!("Error writing file to fd {}: {}", fd, e);
-                                conn.stage = ConnectionStage::Close;
-                            }
-                        },
-                        Err(e) => {
-                            error!("Error reading file on fd {}: {}", fd, e);
-                            conn.stage = ConnectionStage::Close;
                         }
                     }
-                } else {
-                    warn!("No file to send on fd {}", fd);
-                    conn.stage = ConnectionStage::Close;
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Err(e) => {
+                        error!("Error writing headers to fd {}: {}", fd, e);
+                        conn.stage = ConnectionStage::Close;
+                    }
                 }
             }
 
+            ConnectionStage::SendFile => send_file_chunk(fd, conn),
+
+            ConnectionStage::SendParts => send_next_part(fd, conn),
+
+            ConnectionStage::SendListing => send_listing_chunk(fd, conn),
+
             _ => {}
         }
+
+        // Прогресс отправки продлевает --send-timeout
+        if conn.headers_sent as u64 + conn.file_sent != sent_before {
+            conn.last_activity = std::time::Instant::now();
+        }
+
+        if matches!(conn.stage, ConnectionStage::Recv | ConnectionStage::Close) {
+            connection_manager.release_buffer(conn.buffered_bytes);
+            conn.buffered_bytes = 0;
+        }
+
+        // Следующий запрос конвейера уже лежит в буфере, а новых данных в
+        // сокете может и не быть: разбираем его сразу
+        if conn.stage == ConnectionStage::Recv && conn.request_len > 0 {
+            conn.request_started = std::time::Instant::now();
+            process_request_buffer(fd, conn, &config, &worker_pool, &connection_manager);
+        }
     });
 }
 
+/// Дописывает к заголовкам начало файла, чтобы небольшой ответ ушёл одним
+/// вызовом write. Возвращает число перенесённых байт тела
+fn coalesce_body_prefix(fd: i32, conn: &mut Connection) -> usize {
+    conn.body_prefix = 0;
+    if conn.is_head || !conn.segments.is_empty() || conn.headers.len() >= COALESCE_LIMIT {
+        return 0;
+    }
 
-fn parse_http_request(
-    request_str: &str,
-    doc_root: &std::path::PathBuf,
-    max_file_size: u64,
-    fd: i32,
-) -> Result<(Vec<u8>, Option<std::fs::File>, u64, bool), Vec<u8>> {
-    let request_lines: Vec<&str> = request_str.lines().collect();
+    let Some(ref mut file) = conn.file else {
+        return 0;
+    };
 
-    if request_lines.is_empty() {
-        return Err(format_error_response(HttpStatus::BadRequest));
-    }
+    let wanted = ((COALESCE_LIMIT - conn.headers.len()) as u64).min(conn.file_size) as usize;
+    let start = conn.headers.len();
+    conn.headers.resize(start + wanted, 0);
 
-    let first_line: Vec<&str> = request_lines[0].split_whitespace().collect();
-    if first_line.len() < 2 {
-        return Err(format_error_response(HttpStatus::BadRequest));
-    }
+    let read = match file.read(&mut conn.headers[start..]) {
+        Ok(n) => n,
+        Err(e) => {
+            // Ошибку чтения обработает SendFile, как и без объединения
+            debug!("Could not read body prefix on fd {}: {}", fd, e);
+            0
+        }
+    };
 
-    let method = first_line[0];
-    let mut path = first_line[1];
+    conn.headers.truncate(start + read);
+    conn.body_prefix = read;
+    read
+}
 
-    debug!("Parsing request: {} {}", method, path);
+/// Отправляет очередную часть файла. Прочитанный, но ещё не принятый сокетом
+/// остаток хранится в `file_chunk` и досылается следующим вызовом без
+/// повторного чтения файла
+fn send_file_chunk(fd: i32, conn: &mut Connection) {
+    let Some(ref mut file) = conn.file else {
+        warn!("No file to send on fd {}", fd);
+        conn.stage = ConnectionStage::Close;
+        return;
+    };
 
-    if path.contains("..") {
-        warn!("Path traversal attempt on fd {}: {}", fd, path);
-        return Err(format_error_response(HttpStatus::Forbidden));
-    }
+    if conn.file_chunk_sent >= conn.file_chunk.len() {
+        let remaining = conn.file_size.saturating_sub(conn.file_sent);
+        let chunk_len = remaining.min(FILE_CHUNK_SIZE as u64) as usize;
+        conn.file_chunk.resize(chunk_len, 0);
 
-    if path == "/" {
-        path = "/index.html";
+        match file.read(&mut conn.file_chunk) {
+            Ok(0) => {
+                info!(
+                    "File sent completely on fd {} ({} bytes)",
+                    fd, conn.file_sent
+                );
+                conn.finish_response();
+                return;
+            }
+            Ok(bytes_read) => {
+                conn.file_chunk.truncate(bytes_read);
+                conn.file_chunk_sent = 0;
+            }
+            Err(e) => {
+                error!("Error reading file on fd {}: {}", fd, e);
+                conn.stage = ConnectionStage::Close;
+                return;
+            }
+        }
     }
 
-    let file_path = doc_root.join(&path[1..]);
+    let chunk = &conn.file_chunk[conn.file_chunk_sent..];
+    match conn.stream.write(chunk) {
+        Ok(0) => {
+            debug!("Connection closed while sending file on fd {}", fd);
+            conn.stage = ConnectionStage::Close;
+        }
+        Ok(bytes_written) => {
+            trace::record(fd, Direction::Send, &chunk[..bytes_written]);
+            traffic::record(conn.content_category, bytes_written);
+            conn.file_chunk_sent += bytes_written;
+            conn.file_sent += bytes_written as u64;
+            debug!(
+                "Sent {} file bytes on fd {} (total: {}/{})",
+                bytes_written, fd, conn.file_sent, conn.file_size
+            );
 
-    if !file_path.exists() {
-        info!("File not found: {:?}", file_path);
-        return Err(format_error_response(HttpStatus::NotFound));
+            if conn.file_sent >= conn.file_size {
+                info!(
+                    "File sent completely on fd {} ({} bytes)",
+                    fd, conn.file_sent
+                );
+                conn.finish_response();
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+        Err(e) => {
+            error!("Error writing file to fd {}: {}", fd, e);
+            conn.stage = ConnectionStage::Close;
+        }
     }
+}
 
-    if !file_path.is_file() {
-        warn!("Attempt to access directory: {:?}", file_path);
-        return Err(format_error_response(HttpStatus::Forbidden));
-    }
+fn send_listing_chunk(fd: i32, conn: &mut Connection) {
+    let Some(listing) = conn.listing.as_mut() else {
+        warn!("No directory listing to send on fd {}", fd);
+        conn.stage = ConnectionStage::Close;
+        return;
+    };
 
-    let metadata = match std::fs::metadata(&file_path) {
-        Ok(meta) => meta,
+    let chunk = match listing.pending() {
+        Ok([]) => {
+            info!("Directory listing sent completely on fd {}", fd);
+            conn.finish_response();
+            return;
+        }
+        Ok(chunk) => chunk,
         Err(e) => {
-            error!("Error getting metadata for {:?}: {}", file_path, e);
-            return Err(format_error_response(HttpStatus::InternalServerError));
+            // Статус уже отправлен: остаётся только оборвать chunked-тело
+            error!("Error reading directory on fd {}: {}", fd, e);
+            conn.stage = ConnectionStage::Close;
+            return;
         }
     };
 
-    let file_size = metadata.len();
-    if file_size > max_file_size {
-        warn!("File too large: {:?} ({} > {})", file_path, file_size, max_file_size);
-        return Err(format_error_response(HttpStatus::PayloadTooLarge));
+    match conn.stream.write(chunk) {
+        Ok(0) => {
+            debug!("Connection closed while sending listing on fd {}", fd);
+            conn.stage = ConnectionStage::Close;
+        }
+        Ok(n) => {
+            trace::record(fd, Direction::Send, &chunk[..n]);
+            traffic::record(conn.content_category, n);
+            listing.consume(n);
+            conn.file_sent += n as u64;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+        Err(e) => {
+            error!("Error writing listing to fd {}: {}", fd, e);
+            conn.stage = ConnectionStage::Close;
+        }
     }
+}
 
-    let content_type = get_content_type(&file_path);
-    let is_head = method == "HEAD";
+/// Части multipart читаются в тот же буфер соединения `file_chunk`, что и
+/// обычный файл: он выделяется в куче один раз на ответ, а не на стеке
+/// потока пула при каждом событии записи
+fn send_next_part(fd: i32, conn: &mut Connection) {
+    let chunk: &[u8] = match conn.segments.front() {
+        Some(ResponseSegment::Bytes(data)) => &data[conn.segment_sent as usize..],
+        Some(ResponseSegment::File { offset, len }) => {
+            let file = match conn.file {
+                Some(ref mut file) => file,
+                None => {
+                    warn!("No file to send on fd {}", fd);
+                    conn.stage = ConnectionStage::Close;
+                    return;
+                }
+            };
 
-    let file = if !is_head {
-        match std::fs::File::open(&file_path) {
-            Ok(file) => {
-                debug!("File opened for fd {}: {} bytes", fd, file_size);
-                Some(file)
-            }
-            Err(e) => {
-                error!("Error opening file {:?}: {}", file_path, e);
-                return Err(format_error_response(HttpStatus::InternalServerError));
+            let remaining = len - conn.segment_sent;
+            let chunk_len = remaining.min(FILE_CHUNK_SIZE as u64) as usize;
+            conn.file_chunk.resize(chunk_len, 0);
+            let read = file
+                .seek(SeekFrom::Start(offset + conn.segment_sent))
+                .and_then(|_| file.read(&mut conn.file_chunk));
+
+            match read {
+                Ok(0) => {
+                    error!("Unexpected end of file while sending part on fd {}", fd);
+                    conn.stage = ConnectionStage::Close;
+                    return;
+                }
+                Ok(bytes_read) => &conn.file_chunk[..bytes_read],
+                Err(e) => {
+                    error!("Error reading file on fd {}: {}", fd, e);
+                    conn.stage = ConnectionStage::Close;
+                    return;
+                }
             }
         }
-    } else {
-        debug!("HEAD request for {:?}", file_path);
-        None
+        None => {
+            conn.stage = ConnectionStage::Close;
+            return;
+        }
     };
 
-    let headers = format!(
-        "{}Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
-        HttpStatus::Ok.as_response_line(),
-        content_type,
-        file_size
-    );
-
-    Ok((headers.into_bytes(), file, file_size, is_head))
-}
+    match conn.stream.write(chunk) {
+        Ok(0) => {
+            debug!("Connection closed while sending part on fd {}", fd);
+            conn.stage = ConnectionStage::Close;
+        }
+        Ok(bytes_written) => {
+            trace::record(fd, Direction::Send, &chunk[..bytes_written]);
+            traffic::record(conn.content_category, bytes_written);
+            conn.segment_sent += bytes_written as u64;
+            conn.file_sent += bytes_written as u64;
 
+            let segment_len = match conn.segments.front() {
+                Some(ResponseSegment::Bytes(data)) => data.len() as u64,
+                Some(ResponseSegment::File { len, .. }) => *len,
+                None => 0,
+            };
 
+            if conn.segment_sent >= segment_len {
+                conn.segments.pop_front();
+                conn.segment_sent = 0;
+            }
 
-fn format_error_response(status: HttpStatus) -> Vec<u8> {
-    let body = match status {
-        HttpStatus::NotFound => "<html><body><h1>404 Not Found</h1></body></html>",
-        HttpStatus::Forbidden => "<html><body><h1>403 Forbidden</h1></body></html>",
-        HttpStatus::BadRequest => "<html><body><h1>400 Bad Request</h1></body></html>",
-        HttpStatus::PayloadTooLarge => {
-            "<html><body><h1>413 Payload Too Large</h1></body></html>"
+            if conn.segments.is_empty() {
+                info!(
+                    "Multipart response sent completely on fd {} ({} bytes)",
+                    fd, conn.file_sent
+                );
+                conn.finish_response();
+            }
         }
-        HttpStatus::InternalServerError => {
-            "<html><body><h1>500 Internal Server Error</h1></body></html>"
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+        Err(e) => {
+            error!("Error writing part to fd {}: {}", fd, e);
+            conn.stage = ConnectionStage::Close;
         }
-        _ => "<html><body><h1>Error</h1></body></html>",
-    };
+    }
+}
 
-    format!(
-        "{}Content-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
-        status.as_response_line(),
-        body.len(),
+
+/// Проверяет, что корень документов существует. Пропажу и восстановление
+/// каждого корня логируем один раз, а не на каждый запрос
+fn document_root_available(doc_root: &Path) -> bool {
+    let available = doc_root.is_dir();
+    let mut missing = MISSING_ROOTS.lock().unwrap();
+    let was_missing = missing.iter().any(|root| root == doc_root);
+    if available {
+        missing.retain(|root| root != doc_root);
+    } else if !was_missing {
+        missing.push(doc_root.to_path_buf());
+    }
+    drop(missing);
+
+    if !available && !was_missing {
+        error!(
+            "Document root {:?} is no longer available, answering 503 until it is restored",
+            doc_root
+        );
+    } else if available && was_missing {
+        info!("Document root {:?} is available again", doc_root);
+    }
+
+    available
+}
+
+/// Без --follow-symlinks любая символическая ссылка на пути от корня запрещена;
+/// с ним ссылки разрешены, но их цель должна оставаться внутри корня
+fn check_symlinks(doc_root: &Path, file_path: &Path, follow: bool) -> Result<(), HttpStatus> {
+    let Ok(relative) = file_path.strip_prefix(doc_root) else {
+        return Err(HttpStatus::Forbidden);
+    };
+
+    if follow {
+        let (Ok(root), Ok(target)) = (doc_root.canonicalize(), file_path.canonicalize()) else {
+            // Несуществующий путь дальше получит обычный 404
+            return Ok(());
+        };
+        return if target.starts_with(root) {
+            Ok(())
+        } else {
+            Err(HttpStatus::Forbidden)
+        };
+    }
+
+    let mut current = doc_root.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        match std::fs::symlink_metadata(&current) {
+            Ok(meta) if meta.file_type().is_symlink() => return Err(HttpStatus::Forbidden),
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Ответ на сырой запрос без соединения (--dry-run): тот же разбор и та же
+/// сборка заголовков, что и у живого сервера; тело дочитывается из файла
+/// или листинга целиком. Загрузки PUT и удаления DELETE не выполняются
+pub fn dry_run_response(input: &[u8], config: &ServerConfig) -> Vec<u8> {
+    let fd = libc::STDIN_FILENO;
+    let header_end = find_header_end(input).unwrap_or(input.len());
+    let request_data = &input[..header_end];
+
+    let parsed = match request_line_utf8_error(request_data) {
+        Some(_) => Err(HttpStatus::BadRequest),
+        None => HttpRequest::parse(&String::from_utf8_lossy(request_data), config),
+    };
+
+    let (request, response) = match parsed {
+        Ok(request) => {
+            let response = parse_http_request(&request, config, 1, fd, None);
+            (Some(request), response)
+        }
+        Err(status) => (None, Err(format_error_response(status, None))),
+    };
+
+    let mut response = match response {
+        Ok(response) => response,
+        Err(mut error_headers) => {
+            if request.is_some_and(|request| request.method == "HEAD") {
+                strip_response_body(&mut error_headers);
+            }
+            return error_headers;
+        }
+    };
+
+    let mut output = std::mem::take(&mut response.headers);
+    if response.is_head || response.upload.is_some() {
+        return output;
+    }
+
+    let result = if let Some(ref mut listing) = response.listing {
+        loop {
+            match listing.pending() {
+                Ok([]) => break Ok(()),
+                Ok(chunk) => {
+                    let n = chunk.len();
+                    output.extend_from_slice(chunk);
+                    listing.consume(n);
+                }
+                Err(e) => break Err(e),
+            }
+        }
+    } else if let Some(ref mut file) = response.file {
+        if response.segments.is_empty() {
+            file.take(response.file_size)
+                .read_to_end(&mut output)
+                .map(|_| ())
+        } else {
+            response.segments.iter().try_for_each(|segment| match *segment {
+                ResponseSegment::Bytes(ref data) => {
+                    output.extend_from_slice(data);
+                    Ok(())
+                }
+                ResponseSegment::File { offset, len } => {
+                    file.seek(SeekFrom::Start(offset))?;
+                    file.take(len).read_to_end(&mut output).map(|_| ())
+                }
+            })
+        }
+    } else {
+        Ok(())
+    };
+
+    if let Err(e) = result {
+        error!("Error reading response body in dry run: {}", e);
+    }
+    output
+}
+
+fn parse_http_request(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    requests_served: usize,
+    fd: i32,
+    budget: Option<&ConnectionManager>,
+) -> Result<PreparedResponse, Vec<u8>> {
+    let doc_root = &config.document_root;
+    let max_file_size = config.max_file_size;
+
+    let method = request.method.as_str();
+
+    debug!("Parsing request: {} {}", method, request.target);
+
+    let path = match (request.target_form(), method) {
+        (RequestTarget::Asterisk, "OPTIONS") => {
+            return Ok(options_response(request, config, requests_served));
+        }
+        (RequestTarget::Authority, "CONNECT") => {
+            warn!("CONNECT is not supported on fd {}: {}", fd, request.target);
+            return Err(format_error_response(HttpStatus::NotImplemented, Some(request)));
+        }
+        (RequestTarget::Origin(path) | RequestTarget::Absolute(path), method)
+            if method != "CONNECT" =>
+        {
+            path
+        }
+        (form, _) => {
+            warn!(
+                "Request target {:?} is not allowed for {} on fd {}",
+                form, method, fd
+            );
+            return Err(format_error_response(HttpStatus::BadRequest, Some(request)));
+        }
+    };
+
+    if method == "OPTIONS" {
+        return Ok(options_response(request, config, requests_served));
+    }
+
+    let policy = method_policy(method, config);
+    if policy == MethodPolicy::NotImplemented {
+        warn!("Unknown method {} on fd {}", method, fd);
+        return Err(format_error_response(HttpStatus::NotImplemented, Some(request)));
+    }
+
+    if matches!(method, "GET" | "HEAD")
+        && let Some(location) = canonical_location(request, config, path)
+    {
+        debug!("Redirecting to canonical host on fd {}: {}", fd, location);
+        return Ok(redirect_response(
+            request,
+            config,
+            requests_served,
+            HttpStatus::MovedPermanently,
+            &location,
+        ));
+    }
+
+    if request.has_conflicting_length() {
+        warn!("Conflicting message length headers on fd {}: {} {}", fd, method, path);
+        return Err(format_error_response(HttpStatus::BadRequest, Some(request)));
+    }
+
+    if policy == MethodPolicy::Write {
+        let has_length = request.header("Content-Length").is_some();
+        let is_chunked = request
+            .header("Transfer-Encoding")
+            .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+
+        if !has_length && !is_chunked {
+            warn!("{} without Content-Length on fd {}: {}", method, fd, path);
+            return Err(format_error_response(HttpStatus::LengthRequired, Some(request)));
+        }
+
+        if is_chunked {
+            warn!("Unsupported request body on fd {}: {} {}", fd, method, path);
+            return Err(format_error_response(HttpStatus::NotImplemented, Some(request)));
+        }
+    }
+
+    // Длина и обход каталогов проверяются у декодированного и нормализованного
+    // пути: именно он присоединяется к корню документов
+    let normalized = match normalize_path(path) {
+        Ok(normalized) => normalized,
+        Err(status) => {
+            warn!("Malformed request path on fd {}: {}", fd, path);
+            return Err(format_error_response(status, Some(request)));
+        }
+    };
+    let mut path = normalized.as_str();
+
+    if path.len() as u64 > config.max_path_length {
+        warn!(
+            "Path too long on fd {}: {} bytes (limit {})",
+            fd,
+            path.len(),
+            config.max_path_length
+        );
+        return Err(format_error_response(HttpStatus::UriTooLong, Some(request)));
+    }
+
+    if path.contains("..") {
+        warn!("Path traversal attempt on fd {}: {}", fd, path);
+        return Err(format_error_response(HttpStatus::Forbidden, Some(request)));
+    }
+
+    // Доступность проверяется у корня, который обслуживает путь: пропавший
+    // --mount не должен давать поток 404, а пропавший основной корень -
+    // 503 для путей под --mount
+    let (request_root, _) = config.mount_for(path);
+    if !document_root_available(request_root) {
+        debug!("Document root unavailable, answering 503 on fd {}", fd);
+        return Err(format_error_response(HttpStatus::ServiceUnavailable, Some(request)));
+    }
+
+    if policy == MethodPolicy::Write {
+        let (root, relative) = config.mount_for(path);
+        return prepare_upload(request, config, requests_served, &root.join(&relative[1..]), fd);
+    }
+
+    if policy == MethodPolicy::Delete {
+        let (root, relative) = config.mount_for(path);
+        return delete_file(request, config, requests_served, root, &root.join(&relative[1..]), fd);
+    }
+
+    if path == "/"
+        && config.single_file.is_none()
+        && matches!(method, "GET" | "HEAD")
+        && !doc_root.join("index.html").exists()
+    {
+        match config.root_response {
+            RootResponse::NotFound => {}
+            RootResponse::Autoindex => {
+                debug!("No root index, listing document root on fd {}", fd);
+                return directory_response(request, config, requests_served, doc_root, path);
+            }
+            RootResponse::Redirect(ref location) => {
+                debug!("No root index, redirecting to {} on fd {}", location, fd);
+                return Ok(redirect_response(
+                    request,
+                    config,
+                    requests_served,
+                    HttpStatus::Found,
+                    location,
+                ));
+            }
+        }
+    }
+
+    if path == "/" {
+        path = "/index.html";
+    }
+
+    let (doc_root, relative) = config.mount_for(path);
+
+    // В режиме --single-file путь запроса не влияет на отдаваемый файл
+    let single_file = config.single_file.as_ref();
+    let mut file_path = match single_file {
+        Some(single_file) => single_file.clone(),
+        None => doc_root.join(&relative[1..]),
+    };
+    let generated_robots = single_file.is_none()
+        && path == "/robots.txt"
+        && config.robots.is_some()
+        && !file_path.exists();
+
+    if policy == MethodPolicy::NotAllowed {
+        return Err(method_not_allowed(request, config, &file_path, generated_robots, fd));
+    }
+
+    if generated_robots && let Some(policy) = config.robots {
+        debug!("Serving generated robots.txt ({:?}) on fd {}", policy, fd);
+        let body = robots_content::get_robots(policy == RobotsPolicy::Allow);
+        return Ok(generated_response(
+            request,
+            config,
+            requests_served,
+            "text/plain; charset=utf-8",
+            body.into_bytes(),
+        ));
+    }
+
+    if single_file.is_none()
+        && let Err(status) = check_symlinks(doc_root, &file_path, config.follow_symlinks)
+    {
+        warn!("Symlink outside policy on fd {}: {:?}", fd, file_path);
+        return Err(format_error_response(status, Some(request)));
+    }
+
+    let mut metadata = match std::fs::metadata(&file_path) {
+        Ok(meta) => meta,
+        Err(e) => {
+            let status = io_error_status(&e);
+            match status {
+                HttpStatus::NotFound => info!("File not found: {:?}", file_path),
+                HttpStatus::Forbidden => warn!("Permission denied: {:?}", file_path),
+                _ => error!("Error getting metadata for {:?}: {}", file_path, e),
+            }
+            return Err(format_failure_response(status, request, config, &file_path, &e));
+        }
+    };
+
+    if metadata.is_dir() && config.autoindex {
+        let index_path = file_path.join("index.html");
+        match std::fs::metadata(&index_path) {
+            Ok(index_metadata)
+                if index_metadata.is_file()
+                    && check_symlinks(doc_root, &index_path, config.follow_symlinks).is_ok() =>
+            {
+                file_path = index_path;
+                metadata = index_metadata;
+            }
+            _ => return directory_response(request, config, requests_served, &file_path, path),
+        }
+    }
+
+    if !metadata.is_file() {
+        warn!("Attempt to access directory: {:?}", file_path);
+        return Err(format_error_response(HttpStatus::Forbidden, Some(request)));
+    }
+
+    if let Some((variant_path, variant_metadata)) = image_variant(request, config, doc_root, &file_path) {
+        debug!("Serving image variant {:?} for {:?}", variant_path, file_path);
+        file_path = variant_path;
+        metadata = variant_metadata;
+    }
+
+    let file_size = metadata.len();
+    if !config.range_exempts_size_limit && file_size > max_file_size {
+        warn!("File too large: {:?} ({} > {})", file_path, file_size, max_file_size);
+        return Err(format_error_response(HttpStatus::PayloadTooLarge, Some(request)));
+    }
+
+    let mut validators = match config.etag {
+        EtagMode::Weak => Validators::from_metadata(&metadata),
+        EtagMode::Strong => match Validators::strong_for_file(&file_path, &metadata) {
+            Ok(validators) => validators,
+            Err(e) => {
+                warn!("Cannot hash {:?} for ETag, using size and mtime: {}", file_path, e);
+                Validators::from_metadata(&metadata)
+            }
+        },
+    };
+
+    // Готовый .gz отдаётся как gzip-представление файла: Range и Content-Range
+    // относятся к байтам сжатого файла, а не исходного. Выбирается до
+    // проверки 304, чтобы ETag и Vary у 304 совпадали с ответом 200
+    let precompressed = precompressed_variant(request, config, doc_root, &file_path, &metadata);
+
+    // Проверяется до ветки HEAD, чтобы HEAD получал тот же 304, что и GET
+    if validators.not_modified(request.header("If-None-Match"), request.header("If-Modified-Since")) {
+        debug!("Not modified: {:?}", file_path);
+        return Ok(not_modified_response(
+            request,
+            config,
+            requests_served,
+            &file_path,
+            &validators,
+            precompressed.is_some(),
+        ));
+    }
+
+    if config.empty_as_204 && file_size == 0 {
+        debug!("Empty file, answering 204: {:?}", file_path);
+        return Ok(no_content_response(request, config, requests_served, &file_path, &validators));
+    }
+
+    let (body_path, file_size) = match precompressed {
+        Some((ref gz_path, gz_size)) => {
+            debug!("Serving precompressed {:?}", gz_path);
+            validators.etag = validators.gzip_etag();
+            (gz_path.clone(), gz_size)
+        }
+        None => (file_path.clone(), file_size),
+    };
+
+    // Клиент HTTP/1.0 не знает 206 и multipart/byteranges: для него Range
+    // игнорируется и отдаётся весь файл с обычным 200
+    let range = match request.header("Range").filter(|_| !request.is_http10()) {
+        Some(value) => match request.header("If-Range") {
+            Some(if_range) if !validators.if_range_matches(if_range) => {
+                debug!("If-Range does not match for {:?}, serving full file", file_path);
+                RangeRequest::Full
+            }
+            _ => parse_range(value, file_size, config.max_ranges),
+        },
+        None => RangeRequest::Full,
+    };
+
+    let content_type = resolve_content_type(config, &file_path);
+
+    let boundary = multipart_boundary(fd);
+    let mut segments = VecDeque::new();
+    let (status, offset, content_length) = match range {
+        RangeRequest::Full => (HttpStatus::Ok, 0, file_size),
+        RangeRequest::Partial(start, end) => (HttpStatus::PartialContent, start, end - start + 1),
+        RangeRequest::Multi(ref ranges) => {
+            segments = build_multipart_segments(ranges, &content_type, file_size, &boundary);
+            let total = segments.iter().map(|segment| match segment {
+                ResponseSegment::Bytes(data) => data.len() as u64,
+                ResponseSegment::File { len, .. } => *len,
+            });
+            (HttpStatus::PartialContent, 0, total.sum())
+        }
+        RangeRequest::Unsatisfiable => {
+            info!("Range not satisfiable for {:?} (size {})", file_path, file_size);
+            return Err(format_range_not_satisfiable(file_size));
+        }
+    };
+
+    if config.range_exempts_size_limit && content_length > max_file_size {
+        warn!(
+            "Response too large: {:?} ({} > {})",
+            file_path, content_length, max_file_size
+        );
+        return Err(format_error_response(HttpStatus::PayloadTooLarge, Some(request)));
+    }
+
+    let is_head = method == "HEAD";
+
+    // Сжатие на лету только для полного ответа: Content-Range всегда
+    // указывает в исходный файл, поэтому частичный ответ идёт без gzip
+    // HEAD по умолчанию описывает несжатое представление: чтобы узнать длину
+    // сжатого, файл пришлось бы сжать (--head-compressed-length)
+    // Большие файлы (MAX_COMPRESS_SIZE) идут без сжатия: ответ собирается в памяти
+    let compressible = config.gzip && is_compressible(&content_type);
+    let mut gzip_on_the_fly = compressible
+        && precompressed.is_none()
+        && range == RangeRequest::Full
+        && (file_size >= MIN_COMPRESS_SIZE || !accepts_identity(request))
+        && file_size <= MAX_COMPRESS_SIZE
+        && accepts_gzip(request);
+    if gzip_on_the_fly && (!is_head || config.head_compressed_length) {
+        // Бюджет --max-response-buffer резервируется по размеру исходника
+        // до сжатия; если его не хватает, файл уходит несжатым потоком
+        let reserved = budget.map_or(0, |_| file_size as usize);
+        if budget.is_none_or(|budget| budget.try_reserve_buffer(reserved)) {
+            let response = compressed_response(
+                request,
+                config,
+                requests_served,
+                &file_path,
+                &content_type,
+                &validators,
+                reserved,
+            );
+            if let (Err(_), Some(budget)) = (&response, budget) {
+                budget.release_buffer(reserved);
+            }
+            return response;
+        }
+        warn!(
+            "Response buffer budget exhausted, serving {:?} uncompressed on fd {}",
+            file_path, fd
+        );
+        gzip_on_the_fly = false;
+    }
+
+    // --strict-encoding: identity запрещён клиентом, а сжатого ответа нет.
+    // Для HEAD решение то же, что и для GET
+    if config.strict_encoding
+        && precompressed.is_none()
+        && !gzip_on_the_fly
+        && !accepts_identity(request)
+    {
+        info!("No acceptable encoding for {:?}, answering 406", file_path);
+        return Err(format_error_response(HttpStatus::NotAcceptable, Some(request)));
+    }
+
+    let file = if !is_head {
+        match std::fs::File::open(&body_path) {
+            Ok(mut file) => {
+                if offset > 0
+                    && let Err(e) = file.seek(SeekFrom::Start(offset))
+                {
+                    error!("Error seeking file {:?}: {}", file_path, e);
+                    return Err(format_failure_response(
+                        HttpStatus::InternalServerError,
+                        request,
+                        config,
+                        &file_path,
+                        &e,
+                    ));
+                }
+                debug!("File opened for fd {}: {} bytes", fd, content_length);
+                Some(file)
+            }
+            Err(e) => {
+                error!("Error opening file {:?}: {}", file_path, e);
+                let status = io_error_status(&e);
+                return Err(format_failure_response(status, request, config, &file_path, &e));
+            }
+        }
+    } else {
+        debug!("HEAD request for {:?}", file_path);
+        None
+    };
+
+    let content_range = match range {
+        RangeRequest::Partial(start, end) => {
+            format!("Content-Range: bytes {}-{}/{}\r\n", start, end, file_size)
+        }
+        _ => String::new(),
+    };
+
+    let content_type = match range {
+        RangeRequest::Multi(_) => format!("multipart/byteranges; boundary={}", boundary),
+        _ => content_type,
+    };
+
+    let (keep_alive, connection) = connection_headers(request, config, requests_served);
+
+    let mut vary = if compressible || precompressed.is_some() {
+        vec!["Accept-Encoding"]
+    } else {
+        Vec::new()
+    };
+    if is_negotiable_image(config, &file_path) {
+        vary.push("Accept");
+    }
+
+    let content_encoding = if precompressed.is_some() {
+        "Content-Encoding: gzip\r\n"
+    } else {
+        ""
+    };
+
+    let headers = format!(
+        "{}Content-Type: {}\r\nContent-Length: {}\r\n{}Accept-Ranges: bytes\r\n{}ETag: {}\r\nLast-Modified: {}\r\n{}{}{}\r\n",
+        status.as_response_line(),
+        content_type,
+        content_length,
+        content_encoding,
+        content_range,
+        validators.etag,
+        validators.last_modified,
+        cache_control_header(config, &file_path),
+        negotiation_headers(request, config, vary),
+        connection
+    );
+
+    Ok(PreparedResponse {
+        headers: headers.into_bytes(),
+        file,
+        file_size: content_length,
+        is_head,
+        segments,
+        listing: None,
+        keep_alive,
+        upload: None,
+        reserved: 0,
+    })
+}
+
+/// DELETE при --writable: удаляет обычный файл. Каталог существует, но
+/// удалить его нельзя - 405 с методами, которые к нему применимы
+fn delete_file(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    requests_served: usize,
+    root: &Path,
+    target_path: &Path,
+    fd: i32,
+) -> Result<PreparedResponse, Vec<u8>> {
+    if let Err(status) = check_symlinks(root, target_path, config.follow_symlinks) {
+        warn!("Symlink outside policy on fd {}: {:?}", fd, target_path);
+        return Err(format_error_response(status, Some(request)));
+    }
+
+    match std::fs::symlink_metadata(target_path) {
+        Ok(metadata) if metadata.is_dir() => {
+            warn!("Attempt to delete directory on fd {}: {:?}", fd, target_path);
+            return Err(format_method_not_allowed(request, "GET, HEAD, OPTIONS"));
+        }
+        Ok(_) => {}
+        Err(e) => {
+            info!("DELETE on unavailable resource {:?}: {}", target_path, e);
+            return Err(format_error_response(io_error_status(&e), Some(request)));
+        }
+    }
+
+    if config.dry_run {
+        info!("Dry run, not deleting {:?}", target_path);
+    } else if let Err(e) = std::fs::remove_file(target_path) {
+        error!("Error deleting {:?}: {}", target_path, e);
+        let status = io_error_status(&e);
+        return Err(format_failure_response(status, request, config, target_path, &e));
+    } else {
+        info!("Deleted {:?} on fd {}", target_path, fd);
+    }
+
+    let (keep_alive, connection) = connection_headers(request, config, requests_served);
+    // Как и для PUT: клиенту HTTP/1.0 без Content-Length пришлось бы ждать
+    // закрытия соединения
+    let content_length_header = if request.is_http10() {
+        "Content-Length: 0\r\n"
+    } else {
+        ""
+    };
+    let headers = format!(
+        "{}{}{}\r\n",
+        HttpStatus::NoContent.as_response_line(),
+        content_length_header,
+        connection
+    );
+
+    Ok(PreparedResponse {
+        headers: headers.into_bytes(),
+        file: None,
+        file_size: 0,
+        is_head: false,
+        segments: VecDeque::new(),
+        listing: None,
+        keep_alive,
+        upload: None,
+        reserved: 0,
+    })
+}
+
+/// 204 для пустого файла при --empty-as-204: без тела и без Content-Length
+fn no_content_response(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    requests_served: usize,
+    file_path: &Path,
+    validators: &Validators,
+) -> PreparedResponse {
+    let (keep_alive, connection) = connection_headers(request, config, requests_served);
+    // Как и для PUT: клиенту HTTP/1.0 без Content-Length пришлось бы ждать
+    // закрытия соединения
+    let content_length_header = if request.is_http10() {
+        "Content-Length: 0\r\n"
+    } else {
+        ""
+    };
+    let headers = format!(
+        "{}{}ETag: {}\r\nLast-Modified: {}\r\n{}{}{}\r\n",
+        HttpStatus::NoContent.as_response_line(),
+        content_length_header,
+        validators.etag,
+        validators.last_modified,
+        cache_control_header(config, file_path),
+        negotiation_headers(request, config, Vec::new()),
+        connection
+    );
+
+    PreparedResponse {
+        headers: headers.into_bytes(),
+        file: None,
+        file_size: 0,
+        is_head: request.method == "HEAD",
+        segments: VecDeque::new(),
+        listing: None,
+        keep_alive,
+        upload: None,
+        reserved: 0,
+    }
+}
+
+/// `file.gz` рядом с запрошенным файлом при --gzip-static, если клиент
+/// принимает gzip: путь и размер сжатого файла. `.gz` старше исходного
+/// файла считается устаревшим (забыли пересобрать) и не используется.
+/// Ссылки проверяются от корня, выбранного для пути (--mount)
+fn precompressed_variant(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    doc_root: &Path,
+    file_path: &Path,
+    source: &std::fs::Metadata,
+) -> Option<(std::path::PathBuf, u64)> {
+    if !config.gzip_static || !accepts_gzip(request) {
+        return None;
+    }
+
+    let mut gz_path = file_path.as_os_str().to_owned();
+    gz_path.push(".gz");
+    let gz_path = std::path::PathBuf::from(gz_path);
+
+    check_symlinks(doc_root, &gz_path, config.follow_symlinks).ok()?;
+    let metadata = std::fs::metadata(&gz_path).ok().filter(|meta| meta.is_file())?;
+    if let (Ok(gz_modified), Ok(source_modified)) = (metadata.modified(), source.modified())
+        && gz_modified < source_modified
+    {
+        warn!("Ignoring stale {:?}: older than {:?}", gz_path, file_path);
+        return None;
+    }
+    Some((gz_path, metadata.len()))
+}
+
+/// Форматы, которые при --image-variants заменяются более компактными
+const VARIANT_SOURCES: &[&str] = &["image/jpeg", "image/png", "image/gif"];
+
+/// Варианты изображения в порядке предпочтения: расширение и тип
+const IMAGE_VARIANTS: &[(&str, &str)] = &[("avif", "image/avif"), ("webp", "image/webp")];
+
+/// Соседний hero.avif / hero.webp для hero.jpg при --image-variants, если
+/// клиент явно перечислил его тип в Accept (`*/*` и `image/*` не в счёт).
+/// Ссылки проверяются от корня, выбранного для пути (--mount)
+fn image_variant(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    doc_root: &Path,
+    file_path: &Path,
+) -> Option<(std::path::PathBuf, std::fs::Metadata)> {
+    if !config.image_variants || !VARIANT_SOURCES.contains(&get_content_type(file_path)) {
+        return None;
+    }
+    let accept = request.header("Accept")?;
+
+    IMAGE_VARIANTS
+        .iter()
+        .filter(|(_, media)| lists_media(accept, media))
+        .find_map(|(extension, _)| {
+            let variant_path = file_path.with_extension(extension);
+            check_symlinks(doc_root, &variant_path, config.follow_symlinks).ok()?;
+            let metadata = std::fs::metadata(&variant_path).ok().filter(|meta| meta.is_file())?;
+            Some((variant_path, metadata))
+        })
+}
+
+/// Ответ на это изображение зависит от Accept: Vary: Accept
+fn is_negotiable_image(config: &ServerConfig, file_path: &Path) -> bool {
+    let content_type = get_content_type(file_path);
+    config.image_variants
+        && (VARIANT_SOURCES.contains(&content_type)
+            || IMAGE_VARIANTS.iter().any(|(_, media)| *media == content_type))
+}
+
+/// Тип назван в Accept явно и с ненулевым весом
+fn lists_media(accept: &str, media: &str) -> bool {
+    weighted_items(accept)
+        .any(|(range, quality)| range.eq_ignore_ascii_case(media) && quality > 0.0)
+}
+
+fn not_modified_response(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    requests_served: usize,
+    file_path: &Path,
+    validators: &Validators,
+    precompressed: bool,
+) -> PreparedResponse {
+    // Тот же выбор, что и у ответа 200: готовый .gz или сжатие на лету дают
+    // gzip-ETag, и в обоих случаях ответ зависит от Accept-Encoding
+    let compressible = config.gzip && is_compressible(&resolve_content_type(config, file_path));
+    let (etag, mut vary) = if precompressed || (compressible && accepts_gzip(request)) {
+        (validators.gzip_etag(), vec!["Accept-Encoding"])
+    } else if compressible {
+        (validators.etag.clone(), vec!["Accept-Encoding"])
+    } else {
+        (validators.etag.clone(), Vec::new())
+    };
+    if is_negotiable_image(config, file_path) {
+        vary.push("Accept");
+    }
+
+    let (keep_alive, connection) = connection_headers(request, config, requests_served);
+    let headers = format!(
+        "{}ETag: {}\r\nLast-Modified: {}\r\n{}{}{}\r\n",
+        HttpStatus::NotModified.as_response_line(),
+        etag,
+        validators.last_modified,
+        cache_control_header(config, file_path),
+        negotiation_headers(request, config, vary),
+        connection
+    );
+
+    PreparedResponse {
+        headers: headers.into_bytes(),
+        file: None,
+        file_size: 0,
+        is_head: request.method == "HEAD",
+        segments: VecDeque::new(),
+        listing: None,
+        keep_alive,
+        upload: None,
+        reserved: 0,
+    }
+}
+
+/// Список файлов директории без index.html, отрисованный по шаблону --index-template
+fn directory_response(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    requests_served: usize,
+    dir_path: &Path,
+    url_path: &str,
+) -> Result<PreparedResponse, Vec<u8>> {
+    // HTTP/1.0 не знает chunked: такому клиенту список собирается целиком
+    if !request.is_http10() {
+        return streamed_directory_response(request, config, requests_served, dir_path, url_path);
+    }
+
+    let listing = autoindex::load_template(config.index_template.as_deref())
+        .and_then(|template| autoindex::render(dir_path, url_path, &template));
+
+    match listing {
+        Ok(body) => {
+            debug!("Generated directory listing for {:?}", dir_path);
+            Ok(generated_response(
+                request,
+                config,
+                requests_served,
+                "text/html; charset=utf-8",
+                body.into_bytes(),
+            ))
+        }
+        Err(e) => {
+            error!("Error listing directory {:?}: {}", dir_path, e);
+            let status = io_error_status(&e);
+            Err(format_failure_response(status, request, config, dir_path, &e))
+        }
+    }
+}
+
+fn streamed_directory_response(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    requests_served: usize,
+    dir_path: &Path,
+    url_path: &str,
+) -> Result<PreparedResponse, Vec<u8>> {
+    let listing = autoindex::load_template(config.index_template.as_deref())
+        .and_then(|template| Listing::open(dir_path, url_path, &template));
+
+    let listing = match listing {
+        Ok(listing) => listing,
+        Err(e) => {
+            error!("Error listing directory {:?}: {}", dir_path, e);
+            let status = io_error_status(&e);
+            return Err(format_failure_response(status, request, config, dir_path, &e));
+        }
+    };
+
+    debug!("Streaming directory listing for {:?}", dir_path);
+    let (keep_alive, connection) = connection_headers(request, config, requests_served);
+    let headers = format!(
+        "{}Content-Type: text/html; charset=utf-8\r\nTransfer-Encoding: chunked\r\n{}{}\r\n",
+        HttpStatus::Ok.as_response_line(),
+        negotiation_headers(request, config, Vec::new()),
+        connection
+    );
+
+    Ok(PreparedResponse {
+        headers: headers.into_bytes(),
+        file: None,
+        file_size: 0,
+        is_head: request.method == "HEAD",
+        segments: VecDeque::new(),
+        listing: Some(listing),
+        keep_alive,
+        upload: None,
+        reserved: 0,
+    })
+}
+
+fn prepare_upload(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    requests_served: usize,
+    target_path: &Path,
+    fd: i32,
+) -> Result<PreparedResponse, Vec<u8>> {
+    let content_length: u64 = match request.header("Content-Length").map(str::parse) {
+        Some(Ok(len)) => len,
+        _ => return Err(format_error_response(HttpStatus::BadRequest, Some(request))),
+    };
+
+    if content_length > config.max_body_size {
+        warn!(
+            "Request body too large on fd {}: {} > {}",
+            fd, content_length, config.max_body_size
+        );
+        return Err(format_error_response(HttpStatus::PayloadTooLarge, Some(request)));
+    }
+
+    if target_path.is_dir() {
+        warn!("Attempt to upload over directory: {:?}", target_path);
+        return Err(format_error_response(HttpStatus::Forbidden, Some(request)));
+    }
+
+    if !target_path.parent().is_some_and(Path::is_dir) {
+        info!("Upload target directory not found: {:?}", target_path);
+        return Err(format_error_response(HttpStatus::NotFound, Some(request)));
+    }
+
+    let status = if target_path.exists() {
+        HttpStatus::NoContent
+    } else {
+        HttpStatus::Created
+    };
+
+    let upload = match Upload::begin(target_path, config.upload_tmp_dir.as_deref(), content_length) {
+        Ok(upload) => upload,
+        Err(e) => {
+            error!("Error creating upload temp file for {:?}: {}", target_path, e);
+            let status = match e.kind() {
+                std::io::ErrorKind::PermissionDenied => HttpStatus::Forbidden,
+                _ => HttpStatus::InternalServerError,
+            };
+            return Err(format_failure_response(status, request, config, target_path, &e));
+        }
+    };
+
+    debug!(
+        "PUT {:?} on fd {}: expecting {} bytes",
+        target_path, fd, content_length
+    );
+
+    let (keep_alive, connection) = connection_headers(request, config, requests_served);
+    // HTTP/1.0 не запрещает Content-Length у 204, а старые клиенты без него
+    // ждут закрытия соединения
+    let content_length_header = if status == HttpStatus::NoContent && !request.is_http10() {
+        ""
+    } else {
+        "Content-Length: 0\r\n"
+    };
+    let headers = format!(
+        "{}{}{}\r\n",
+        status.as_response_line(),
+        content_length_header,
+        connection
+    );
+
+    Ok(PreparedResponse {
+        headers: headers.into_bytes(),
+        file: None,
+        file_size: 0,
+        is_head: false,
+        segments: VecDeque::new(),
+        listing: None,
+        keep_alive,
+        upload: Some(upload),
+        reserved: 0,
+    })
+}
+
+/// Ответ, сжатый gzip целиком в памяти; учитывается в бюджете --max-response-buffer
+fn compressed_response(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    requests_served: usize,
+    file_path: &Path,
+    content_type: &str,
+    validators: &Validators,
+    reserved: usize,
+) -> Result<PreparedResponse, Vec<u8>> {
+    let body = match gzip_file(file_path, config.compression_level) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Error compressing file {:?}: {}", file_path, e);
+            let status = io_error_status(&e);
+            return Err(format_failure_response(status, request, config, file_path, &e));
+        }
+    };
+
+    debug!("Compressed {:?} to {} bytes", file_path, body.len());
+
+    let (keep_alive, connection) = connection_headers(request, config, requests_served);
+    let etag = validators.gzip_etag();
+
+    let mut headers = format!(
+        "{}Content-Type: {}\r\nContent-Length: {}\r\nContent-Encoding: gzip\r\nETag: {}\r\nLast-Modified: {}\r\n{}{}{}\r\n",
+        HttpStatus::Ok.as_response_line(),
+        content_type,
+        body.len(),
+        etag,
+        validators.last_modified,
+        cache_control_header(config, file_path),
+        negotiation_headers(request, config, vec!["Accept-Encoding"]),
+        connection
+    )
+    .into_bytes();
+
+    let is_head = request.method == "HEAD";
+    if !is_head {
+        headers.extend_from_slice(&body);
+    }
+
+    Ok(PreparedResponse {
+        headers,
+        file: None,
+        file_size: 0,
+        is_head,
+        segments: VecDeque::new(),
+        listing: None,
+        keep_alive,
+        upload: None,
+        reserved,
+    })
+}
+
+fn cache_control_header(config: &ServerConfig, file_path: &Path) -> String {
+    config
+        .cache_control_for(file_path)
+        .map(|value| format!("Cache-Control: {}\r\n", value))
+        .unwrap_or_default()
+}
+
+/// Заголовки, зависящие от согласования: CORS и единый Vary со списком всех
+/// заголовков запроса, от которых зависит ответ (без повторов)
+fn negotiation_headers(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    mut vary: Vec<&'static str>,
+) -> String {
+    let mut headers = String::new();
+
+    if config.cors_origin.iter().any(|origin| origin == "*") {
+        headers.push_str("Access-Control-Allow-Origin: *\r\n");
+    } else if !config.cors_origin.is_empty() {
+        vary.push("Origin");
+        if let Some(origin) = request.header("Origin")
+            && config.cors_origin.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        {
+            headers.push_str(&format!("Access-Control-Allow-Origin: {}\r\n", origin));
+        }
+    }
+
+    let mut fields: Vec<&str> = Vec::new();
+    for field in vary {
+        if !fields.iter().any(|known| known.eq_ignore_ascii_case(field)) {
+            fields.push(field);
+        }
+    }
+
+    if !fields.is_empty() {
+        headers.push_str(&format!("Vary: {}\r\n", fields.join(", ")));
+    }
+
+    headers
+}
+
+/// Что сервер делает с методом запроса (OPTIONS отвечается раньше)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MethodPolicy {
+    /// GET и HEAD: отдача ресурса
+    Read,
+    /// PUT при --writable: загрузка файла
+    Write,
+    /// DELETE при --writable: удаление файла
+    Delete,
+    /// Известный метод без обработчика в этой конфигурации: 405 с Allow
+    NotAllowed,
+    /// Нестандартный метод: 501
+    NotImplemented,
+}
+
+/// Единая политика методов. PATCH и POST обработчиков не имеют и в режиме
+/// --writable; список в Allow строит allowed_methods
+fn method_policy(method: &str, config: &ServerConfig) -> MethodPolicy {
+    match method {
+        "GET" | "HEAD" => MethodPolicy::Read,
+        "PUT" if config.writable => MethodPolicy::Write,
+        "DELETE" if config.writable => MethodPolicy::Delete,
+        "PUT" | "POST" | "DELETE" | "PATCH" | "TRACE" => MethodPolicy::NotAllowed,
+        _ => MethodPolicy::NotImplemented,
+    }
+}
+
+/// Ответ на метод, не разрешённый для ресурса: 405 с Allow, если ресурс
+/// существует, иначе статус по ошибке доступа к нему (404/403)
+fn method_not_allowed(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    file_path: &Path,
+    generated: bool,
+    fd: i32,
+) -> Vec<u8> {
+    let method = request.method.as_str();
+    if !generated && let Err(e) = std::fs::metadata(file_path) {
+        let status = io_error_status(&e);
+        info!("{} on unavailable resource {:?}: {}", method, file_path, e);
+        return format_error_response(status, Some(request));
+    }
+
+    warn!("Method {} not allowed for {:?} on fd {}", method, file_path, fd);
+    format_method_not_allowed(request, allowed_methods(config))
+}
+
+fn allowed_methods(config: &ServerConfig) -> &'static str {
+    if config.writable {
+        "GET, HEAD, OPTIONS, PUT, DELETE"
+    } else {
+        "GET, HEAD, OPTIONS"
+    }
+}
+
+fn options_response(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    requests_served: usize,
+) -> PreparedResponse {
+    let (keep_alive, connection) = connection_headers(request, config, requests_served);
+
+    // Preflight отличается от обычного OPTIONS заголовком
+    // Access-Control-Request-Method
+    let is_preflight = !config.cors_origin.is_empty()
+        && request.header("Access-Control-Request-Method").is_some();
+    let max_age = match config.cors_max_age {
+        Some(seconds) if is_preflight => format!("Access-Control-Max-Age: {}\r\n", seconds),
+        _ => String::new(),
+    };
+
+    let headers = format!(
+        "{}Allow: {}\r\nContent-Length: 0\r\n{}{}{}\r\n",
+        HttpStatus::Ok.as_response_line(),
+        allowed_methods(config),
+        negotiation_headers(request, config, Vec::new()),
+        max_age,
+        connection
+    );
+
+    PreparedResponse {
+        headers: headers.into_bytes(),
+        file: None,
+        file_size: 0,
+        is_head: false,
+        segments: VecDeque::new(),
+        listing: None,
+        keep_alive,
+        upload: None,
+        reserved: 0,
+    }
+}
+
+fn connection_headers(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    requests_served: usize,
+) -> (bool, String) {
+    let keep_alive = request.wants_keep_alive() && requests_served < config.keepalive_max;
+    let connection = if keep_alive {
+        format!(
+            "Connection: keep-alive\r\nKeep-Alive: timeout={}, max={}\r\n",
+            config.keepalive_timeout,
+            config.keepalive_max - requests_served
+        )
+    } else {
+        "Connection: close\r\n".to_string()
+    };
+
+    (keep_alive, connection)
+}
+
+/// Адрес на --canonical-host, если запрос пришёл на другой хост. Хост и
+/// схема берутся из Forwarded при --trust-proxy; запрос без Host не
+/// перенаправляется
+fn canonical_location(request: &HttpRequest, config: &ServerConfig, path: &str) -> Option<String> {
+    let canonical = config.canonical_host.as_deref()?;
+    let forwarded = config
+        .trust_proxy
+        .then(|| Forwarded::from_request(request))
+        .flatten()
+        .unwrap_or_default();
+
+    let host = forwarded.host.as_deref().or(request.header("Host"))?;
+    if host.trim().eq_ignore_ascii_case(canonical) {
+        return None;
+    }
+
+    let scheme = forwarded.proto.as_deref().unwrap_or("http");
+    let query = request
+        .target
+        .split_once('?')
+        .map_or(String::new(), |(_, query)| format!("?{}", query));
+    Some(format!("{}://{}{}{}", scheme, canonical, path, query))
+}
+
+fn redirect_response(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    requests_served: usize,
+    status: HttpStatus,
+    location: &str,
+) -> PreparedResponse {
+    let (keep_alive, connection) = connection_headers(request, config, requests_served);
+    let headers = format!(
+        "{}Location: {}\r\nContent-Length: 0\r\n{}\r\n",
+        status.as_response_line(),
+        location,
+        connection
+    );
+
+    PreparedResponse {
+        headers: headers.into_bytes(),
+        file: None,
+        file_size: 0,
+        is_head: request.method == "HEAD",
+        segments: VecDeque::new(),
+        listing: None,
+        keep_alive,
+        upload: None,
+        reserved: 0,
+    }
+}
+
+/// Ответ со сгенерированным телом, которое целиком помещается в буфер заголовков
+fn generated_response(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    requests_served: usize,
+    content_type: &str,
+    body: Vec<u8>,
+) -> PreparedResponse {
+    let (keep_alive, connection) = connection_headers(request, config, requests_served);
+    let is_head = request.method == "HEAD";
+
+    let mut headers = format!(
+        "{}Content-Type: {}\r\nContent-Length: {}\r\n{}{}\r\n",
+        HttpStatus::Ok.as_response_line(),
+        content_type,
+        body.len(),
+        negotiation_headers(request, config, Vec::new()),
+        connection
+    )
+    .into_bytes();
+
+    if !is_head {
+        headers.extend_from_slice(&body);
+    }
+
+    PreparedResponse {
+        headers,
+        file: None,
+        file_size: 0,
+        is_head,
+        segments: VecDeque::new(),
+        listing: None,
+        keep_alive,
+        upload: None,
+        reserved: 0,
+    }
+}
+
+fn multipart_boundary(fd: i32) -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("{:016x}{:08x}", nanos as u64, fd)
+}
+
+fn build_multipart_segments(
+    ranges: &[(u64, u64)],
+    content_type: &str,
+    file_size: u64,
+    boundary: &str,
+) -> VecDeque<ResponseSegment> {
+    let mut segments = VecDeque::new();
+
+    for &(start, end) in ranges {
+        let part_headers = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, content_type, start, end, file_size
+        );
+        segments.push_back(ResponseSegment::Bytes(part_headers.into_bytes()));
+        segments.push_back(ResponseSegment::File {
+            offset: start,
+            len: end - start + 1,
+        });
+    }
+
+    segments.push_back(ResponseSegment::Bytes(
+        format!("\r\n--{}--\r\n", boundary).into_bytes(),
+    ));
+    segments
+}
+
+/// Статус для ошибки файловой системы: нет доступа (EACCES) - 403,
+/// файл исчез - 404, остальные ошибки ввода-вывода - 500
+fn io_error_status(e: &std::io::Error) -> HttpStatus {
+    match e.kind() {
+        std::io::ErrorKind::PermissionDenied => HttpStatus::Forbidden,
+        std::io::ErrorKind::NotFound => HttpStatus::NotFound,
+        _ => HttpStatus::InternalServerError,
+    }
+}
+
+/// Ответ на сбой при работе с файлом. Тело 500 по умолчанию общее: путь и
+/// текст ошибки остаются в логе и попадают в тело только с --debug-errors
+fn format_failure_response(
+    status: HttpStatus,
+    request: &HttpRequest,
+    config: &ServerConfig,
+    path: &Path,
+    e: &std::io::Error,
+) -> Vec<u8> {
+    if status != HttpStatus::InternalServerError || !config.debug_errors {
+        return format_error_response(status, Some(request));
+    }
+
+    let detail = format!("{}: {}", path.display(), e);
+    let (content_type, body) = if wants_json(Some(request)) {
+        let body = format!(
+            "{{\"error\":{{\"code\":{},\"message\":\"{}\",\"detail\":\"{}\"}}}}",
+            status.code(),
+            status.text(),
+            escape_json(&detail)
+        );
+        ("application/json", body)
+    } else {
+        let body = format!(
+            "<html><body><h1>{} {}</h1><pre>{}</pre></body></html>",
+            status.code(),
+            status.text(),
+            autoindex::escape_html(&detail)
+        );
+        ("text/html", body)
+    };
+
+    format_error_response_with(status, "", content_type, &body)
+}
+
+fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            ch if ch.is_control() => escaped.push_str(&format!("\\u{:04x}", ch as u32)),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+fn format_range_not_satisfiable(file_size: u64) -> Vec<u8> {
+    format_error_response_with(
+        HttpStatus::RangeNotSatisfiable,
+        &format!("Content-Range: bytes */{}\r\n", file_size),
+        "text/html",
+        "",
+    )
+}
+
+/// Ответ с ошибкой; формат тела выбирается по Accept запроса, если он
+/// уже разобран
+fn format_error_response(status: HttpStatus, request: Option<&HttpRequest>) -> Vec<u8> {
+    let (content_type, body) = error_body(status, request);
+    format_error_response_with(status, "", content_type, &body)
+}
+
+/// Тело ошибки: JSON для клиентов, предпочитающих application/json,
+/// иначе HTML (браузеры, отсутствующий или неразобранный запрос)
+fn error_body(status: HttpStatus, request: Option<&HttpRequest>) -> (&'static str, String) {
+    if wants_json(request) {
+        let body = format!(
+            "{{\"error\":{{\"code\":{},\"message\":\"{}\"}}}}",
+            status.code(),
+            status.text()
+        );
+        ("application/json", body)
+    } else {
+        let body = format!(
+            "<html><body><h1>{} {}</h1></body></html>",
+            status.code(),
+            status.text()
+        );
+        ("text/html", body)
+    }
+}
+
+/// 405 с заголовком Allow; разрешённые методы перечисляются и в теле,
+/// чтобы их было видно в браузере
+fn format_method_not_allowed(request: &HttpRequest, allowed: &str) -> Vec<u8> {
+    let status = HttpStatus::MethodNotAllowed;
+    let methods: Vec<&str> = allowed.split(',').map(str::trim).collect();
+
+    let (content_type, body) = if wants_json(Some(request)) {
+        let list: Vec<String> = methods.iter().map(|m| format!("\"{}\"", m)).collect();
+        let body = format!(
+            "{{\"error\":{{\"code\":{},\"message\":\"{}\",\"allowed\":[{}]}}}}",
+            status.code(),
+            status.text(),
+            list.join(",")
+        );
+        ("application/json", body)
+    } else {
+        let body = format!(
+            "<html><body><h1>{} {}</h1><p>Allowed methods: {}</p></body></html>",
+            status.code(),
+            status.text(),
+            methods.join(", ")
+        );
+        ("text/html", body)
+    };
+
+    format_error_response_with(status, &format!("Allow: {}\r\n", allowed), content_type, &body)
+}
+
+fn wants_json(request: Option<&HttpRequest>) -> bool {
+    request
+        .and_then(|request| request.header("Accept"))
+        .is_some_and(prefers_json)
+}
+
+/// application/json предпочтительнее text/html по весам Accept; при равных
+/// весах (например, `*/*`) остаётся HTML
+fn prefers_json(accept: &str) -> bool {
+    let json = media_quality(accept, "application/json");
+    json > 0.0 && json > media_quality(accept, "text/html")
+}
+
+/// Вес типа в Accept по самому точному подходящему диапазону
+/// (`type/subtype`, затем `type/*`, затем `*/*`); 0, если тип не принимается
+fn media_quality(accept: &str, media: &str) -> f32 {
+    let main_type = media.split('/').next().unwrap_or_default();
+    let mut best: Option<(u8, f32)> = None;
+
+    for (range, quality) in weighted_items(accept) {
+        let specificity = match range.split_once('/') {
+            _ if range.eq_ignore_ascii_case(media) => 3,
+            Some((range_type, "*")) if range_type.eq_ignore_ascii_case(main_type) => 2,
+            Some(("*", "*")) => 1,
+            _ => continue,
+        };
+
+        if best.is_none_or(|(current, _)| specificity > current) {
+            best = Some((specificity, quality));
+        }
+    }
+
+    best.map_or(0.0, |(_, quality)| quality)
+}
+
+/// Единое оформление ошибок: всегда Content-Type и Content-Length,
+/// соответствующий телу (в том числе пустому)
+fn format_error_response_with(
+    status: HttpStatus,
+    extra_headers: &str,
+    content_type: &str,
+    body: &str,
+) -> Vec<u8> {
+    format!(
+        "{}Content-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status.as_response_line(),
+        content_type,
+        body.len(),
+        extra_headers,
         body
     )
     .into_bytes()
 }
 
-fn get_content_type(file_path: &std::path::PathBuf) -> &'static str {
+/// Для HEAD тело ошибки отбрасывается, заголовок Content-Length сохраняется
+fn strip_response_body(response: &mut Vec<u8>) {
+    if let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+        response.truncate(end + 4);
+    }
+}
+
+/// Тип по расширению (для неизвестных расширений при --sniff-content-type -
+/// по первым байтам файла); к text/* добавляется кодировка из конфигурации
+fn resolve_content_type(config: &ServerConfig, file_path: &Path) -> String {
+    let mut content_type = get_content_type(file_path);
+    if content_type == "application/octet-stream" && config.sniff_content_type {
+        content_type = sniff_content_type(file_path).unwrap_or(content_type);
+    }
+
+    if content_type.starts_with("text/") {
+        format!("{}; charset={}", content_type, config.charset_for(file_path))
+    } else {
+        content_type.to_string()
+    }
+}
+
+/// Распознаёт HTML, PNG, JPEG, GIF, PDF и текст в UTF-8 по сигнатуре
+fn sniff_content_type(file_path: &Path) -> Option<&'static str> {
+    let mut head = [0u8; 512];
+    let mut file = std::fs::File::open(file_path).ok()?;
+    let len = file.read(&mut head).ok()?;
+    let head = &head[..len];
+
+    let signatures: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+    ];
+    if let Some((_, mime)) = signatures.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return Some(mime);
+    }
+
+    if head.is_empty() || head.contains(&0) {
+        return None;
+    }
+
+    // Последний символ UTF-8 мог обрезаться на границе прочитанного
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+
+    let start = text.trim_start().to_ascii_lowercase();
+    let is_html = ["<!doctype html", "<html", "<head", "<body"]
+        .iter()
+        .any(|tag| start.starts_with(tag));
+
+    Some(if is_html { "text/html" } else { "text/plain" })
+}
+
+fn get_content_type(file_path: &Path) -> &'static str {
     let ext = file_path
         .extension()
         .and_then(|s| s.to_str())
@@ -309,14 +2532,19 @@ fn get_content_type(file_path: &std::path::PathBuf) -> &'static str {
     let mime_types = &[
         ("html", "text/html"),
         ("css", "text/css"),
-        ("js", "application/javascript"),
+        ("js", "text/javascript"),
+        ("mjs", "text/javascript"),
+        ("cjs", "text/javascript"),
         ("png", "image/png"),
         ("jpg", "image/jpeg"),
         ("jpeg", "image/jpeg"),
         ("gif", "image/gif"),
         ("svg", "image/svg+xml"),
+        ("webp", "image/webp"),
+        ("avif", "image/avif"),
         ("ico", "image/x-icon"),
         ("json", "application/json"),
+        ("map", "application/json"),
         ("txt", "text/plain"),
     ];
 
@@ -327,7 +2555,51 @@ fn get_content_type(file_path: &std::path::PathBuf) -> &'static str {
         .unwrap_or("application/octet-stream")
 }
 
-fn contains_double_newline(buffer: &[u8]) -> bool {
+/// Смещение первого невалидного байта UTF-8 в строке запроса. Значения
+/// заголовков по-прежнему разбираются с заменой невалидных байт
+fn request_line_utf8_error(request: &[u8]) -> Option<usize> {
+    let line_end = request
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .unwrap_or(request.len());
+
+    std::str::from_utf8(&request[..line_end])
+        .err()
+        .map(|e| e.valid_up_to())
+}
+
+/// Длина тела, которое нужно получить в буфер до разбора запроса: по
+/// Content-Length. Тело загрузки PUT читается потоком в RecvBody, а
+/// chunked и некорректные длины отклоняет разбор запроса
+fn buffered_body_len(head: &[u8], config: &ServerConfig) -> usize {
+    let head = String::from_utf8_lossy(head);
+    if config.writable && head.starts_with("PUT ") {
+        return 0;
+    }
+
+    head.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Сканирует только новые байты буфера плюс 3 байта перекрытия, чтобы найти
+/// разделитель, попавший на границу двух чтений; строки заголовка считаются
+/// по тем же новым байтам
+fn scan_header_end(conn: &mut Connection) -> Option<usize> {
+    let scan_from = conn.scan_offset.saturating_sub(3);
+    conn.header_lines += conn.request_buffer[conn.scan_offset..conn.request_len]
+        .iter()
+        .filter(|&&byte| byte == b'\n')
+        .count();
+    conn.scan_offset = conn.request_len;
+    find_header_end(&conn.request_buffer[scan_from..conn.request_len]).map(|end| scan_from + end)
+}
+
+/// Возвращает позицию сразу после конца заголовков (`\r\n\r\n` или `\n\n`)
+fn find_header_end(buffer: &[u8]) -> Option<usize> {
     let len = buffer.len();
     for i in 0..len.saturating_sub(3) {
         if buffer[i] == b'\r'
@@ -335,15 +2607,1085 @@ fn contains_double_newline(buffer: &[u8]) -> bool {
             && buffer[i + 2] == b'\r'
             && buffer[i + 3] == b'\n'
         {
-            return true;
+            return Some(i + 4);
         }
     }
 
     for i in 0..len.saturating_sub(1) {
         if buffer[i] == b'\n' && buffer[i + 1] == b'\n' {
-            return true;
+            return Some(i + 2);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::config::Mount;
+    use std::net::TcpListener;
+
+    /// Корень документов во временной директории с файлами `files`
+    fn document_root(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        for (path, content) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        root
+    }
+
+    fn prepare(
+        request: &str,
+        config: &ServerConfig,
+        budget: &ConnectionManager,
+    ) -> PreparedResponse {
+        let request = HttpRequest::parse(request, config).unwrap();
+        match parse_http_request(&request, config, 0, -1, Some(budget)) {
+            Ok(response) => response,
+            Err(error) => panic!("{}", String::from_utf8_lossy(&error)),
+        }
+    }
+
+    #[test]
+    fn unsatisfiable_range_reports_file_length() {
+        let response = String::from_utf8(format_range_not_satisfiable(1234)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 416 "), "{}", response);
+        assert!(response.contains("\r\nContent-Range: bytes */1234\r\n"));
+        assert!(response.contains("\r\nContent-Length: 0\r\n"));
+    }
+
+    #[test]
+    fn base64_encode_rfc4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (input, expected) in vectors {
+            assert_eq!(
+                base64_encode(input.as_bytes()),
+                expected,
+                "input {:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_input() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"YWRtaW46c2VjcmV0", b"YWRtaW46c2VjcmV0"));
+        assert!(!constant_time_eq(b"YWRtaW46c2VjcmV0", b"YWRtaW46c2VjcmV1"));
+        assert!(!constant_time_eq(b"XWRtaW46c2VjcmV0", b"YWRtaW46c2VjcmV0"));
+        assert!(!constant_time_eq(b"YWRtaW46", b"YWRtaW46c2VjcmV0"));
+    }
+
+    #[test]
+    fn compression_reserves_budget_or_falls_back_to_streaming() {
+        let root = document_root("gzip-budget", &[("page.txt", &[b'a'; 4096])]);
+        let config = ServerConfig {
+            document_root: root.clone(),
+            gzip: true,
+            ..Default::default()
+        };
+        let request = "GET /page.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n";
+        let listener = || TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let budget = ConnectionManager::new(listener());
+        let compressed = prepare(request, &config, &budget);
+        assert!(compressed.file.is_none());
+        assert_eq!(compressed.reserved, 4096);
+        assert!(
+            String::from_utf8_lossy(&compressed.headers).contains("\r\nContent-Encoding: gzip\r\n")
+        );
+        assert!(!budget.try_reserve_buffer(67108864 - 4096 + 1));
+
+        let small = ServerConfig {
+            max_response_buffer: 1024,
+            ..Default::default()
+        };
+        let budget = ConnectionManager::with_config(listener(), &small);
+        let streamed = prepare(request, &config, &budget);
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(streamed.file.is_some());
+        assert_eq!(streamed.reserved, 0);
+        assert!(!String::from_utf8_lossy(&streamed.headers).contains("Content-Encoding"));
+        assert!(budget.try_reserve_buffer(1024));
+    }
+
+    fn status_line(response: &[u8]) -> String {
+        let text = String::from_utf8_lossy(response);
+        text.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn method_policy_matrix() {
+        let root = document_root("method-matrix", &[("file.txt", b"content")]);
+        let cases = [
+            ("GET", 200, 200),
+            ("HEAD", 200, 200),
+            ("OPTIONS", 200, 200),
+            ("PUT", 405, 204),
+            ("DELETE", 405, 204),
+            ("POST", 405, 405),
+            ("PATCH", 405, 405),
+            ("TRACE", 405, 405),
+            ("BREW", 501, 501),
+        ];
+        for writable in [false, true] {
+            let config = ServerConfig {
+                document_root: root.clone(),
+                writable,
+                dry_run: true,
+                ..Default::default()
+            };
+            for (method, read_only_status, writable_status) in cases {
+                let request = format!("{} /file.txt HTTP/1.1\r\nContent-Length: 0\r\n\r\n", method);
+                let response =
+                    String::from_utf8(dry_run_response(request.as_bytes(), &config)).unwrap();
+                let expected = if writable {
+                    writable_status
+                } else {
+                    read_only_status
+                };
+                assert!(
+                    status_line(response.as_bytes())
+                        .starts_with(&format!("HTTP/1.1 {} ", expected)),
+                    "{} (writable: {}): {}",
+                    method,
+                    writable,
+                    response
+                );
+                if expected == 405 {
+                    let allow = if writable {
+                        "GET, HEAD, OPTIONS, PUT, DELETE"
+                    } else {
+                        "GET, HEAD, OPTIONS"
+                    };
+                    assert!(
+                        response.contains(&format!("\r\nAllow: {}\r\n", allow)),
+                        "{}",
+                        response
+                    );
+                }
+            }
+        }
+        assert!(root.join("file.txt").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn delete_removes_files_only() {
+        let root = document_root(
+            "delete",
+            &[("file.txt", b"content"), ("dir/inner.txt", b"inner")],
+        );
+        let config = ServerConfig {
+            document_root: root.clone(),
+            writable: true,
+            ..Default::default()
+        };
+        let delete = |path: &str| {
+            let request = format!("DELETE {} HTTP/1.1\r\n\r\n", path);
+            dry_run_response(request.as_bytes(), &config)
+        };
+
+        assert_eq!(status_line(&delete("/file.txt")), "HTTP/1.1 204 No Content");
+        assert!(!root.join("file.txt").exists());
+        assert_eq!(status_line(&delete("/file.txt")), "HTTP/1.1 404 Not Found");
+
+        let directory = String::from_utf8(delete("/dir")).unwrap();
+        assert!(directory.starts_with("HTTP/1.1 405 "), "{}", directory);
+        assert!(
+            directory.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"),
+            "{}",
+            directory
+        );
+        assert!(root.join("dir/inner.txt").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn path_length_is_checked_after_decoding() {
+        let root = document_root("path-length", &[]);
+        std::fs::create_dir_all(&root).unwrap();
+        let config = ServerConfig {
+            document_root: root.clone(),
+            max_path_length: 16,
+            ..Default::default()
+        };
+        // "/" и 15 или 16 закодированных "A": 16 и 17 байт после декодирования
+        let request = |letters: usize| {
+            let request = format!("GET /{} HTTP/1.1\r\n\r\n", "%41".repeat(letters));
+            status_line(&dry_run_response(request.as_bytes(), &config))
+        };
+
+        assert_eq!(request(15), "HTTP/1.1 404 Not Found");
+        assert_eq!(request(16), "HTTP/1.1 414 URI Too Long");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn header_end_is_found_one_byte_at_a_time_without_rescanning() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut conn =
+            Connection::new(std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let request = b"GET / HTTP/1.1\r\nHost: test\r\nAccept: */*\r\n\r\n";
+
+        for (i, &byte) in request.iter().enumerate() {
+            conn.request_buffer[i] = byte;
+            conn.request_len = i + 1;
+            let expected = (i + 1 == request.len()).then_some(request.len());
+            assert_eq!(
+                scan_header_end(&mut conn),
+                expected,
+                "after {} bytes",
+                i + 1
+            );
+            assert_eq!(conn.scan_offset, i + 1);
+            // Просмотренные байты за пределами перекрытия больше не читаются:
+            // подложенный в них разделитель не должен найтись
+            if i == 7 {
+                conn.request_buffer[..4].copy_from_slice(b"\r\n\r\n");
+            }
+        }
+        assert_eq!(conn.header_lines, 4);
+    }
+
+    #[test]
+    fn robots_txt_is_generated_unless_present_on_disk() {
+        let root = document_root("robots", &[]);
+        std::fs::create_dir_all(&root).unwrap();
+        let robots = |policy: Option<RobotsPolicy>| {
+            let config = ServerConfig {
+                document_root: root.clone(),
+                robots: policy,
+                ..Default::default()
+            };
+            String::from_utf8(dry_run_response(
+                b"GET /robots.txt HTTP/1.1\r\n\r\n",
+                &config,
+            ))
+            .unwrap()
+        };
+
+        assert!(robots(None).starts_with("HTTP/1.1 404 "));
+        let allow = robots(Some(RobotsPolicy::Allow));
+        assert!(allow.starts_with("HTTP/1.1 200 OK\r\n"), "{}", allow);
+        assert!(allow.contains("\r\nContent-Type: text/plain; charset=utf-8\r\n"));
+        assert!(
+            allow.ends_with("\r\n\r\nUser-agent: *\nDisallow:\n"),
+            "{}",
+            allow
+        );
+        let deny = robots(Some(RobotsPolicy::Deny));
+        assert!(
+            deny.ends_with("\r\n\r\nUser-agent: *\nDisallow: /\n"),
+            "{}",
+            deny
+        );
+
+        std::fs::write(
+            root.join("robots.txt"),
+            "User-agent: bot\nDisallow: /private\n",
+        )
+        .unwrap();
+        for policy in [None, Some(RobotsPolicy::Allow), Some(RobotsPolicy::Deny)] {
+            let response = robots(policy);
+            assert!(
+                response.ends_with("\r\n\r\nUser-agent: bot\nDisallow: /private\n"),
+                "{}",
+                response
+            );
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Заголовки и тело ответа
+    fn split_response(response: &[u8]) -> (String, &[u8]) {
+        let end = find_header_end(response).expect("complete header section");
+        (
+            String::from_utf8_lossy(&response[..end]).into_owned(),
+            &response[end..],
+        )
+    }
+
+    #[test]
+    fn error_functions_frame_errors_identically() {
+        let request =
+            HttpRequest::parse("GET /missing HTTP/1.1\r\n\r\n", &ServerConfig::default()).unwrap();
+        let config = ServerConfig::default();
+        let error = std::io::Error::other("disk on fire");
+
+        for status in [
+            HttpStatus::Forbidden,
+            HttpStatus::NotFound,
+            HttpStatus::InternalServerError,
+        ] {
+            let plain = format_error_response(status, Some(&request));
+            let failure = format_failure_response(
+                status,
+                &request,
+                &config,
+                Path::new("/srv/secret"),
+                &error,
+            );
+            assert_eq!(plain, failure, "{:?}", status);
+
+            let (headers, body) = split_response(&plain);
+            assert!(
+                headers.contains("\r\nContent-Type: text/html\r\n"),
+                "{}",
+                headers
+            );
+            assert!(
+                headers.contains(&format!("\r\nContent-Length: {}\r\n", body.len())),
+                "{}",
+                headers
+            );
+
+            let mut head = plain.clone();
+            strip_response_body(&mut head);
+            assert_eq!(split_response(&head), (headers, &b""[..]));
+        }
+    }
+
+    #[test]
+    fn negotiation_headers_merge_vary_and_cors() {
+        let request = |origin: &str| {
+            let request = format!("GET / HTTP/1.1\r\nOrigin: {}\r\n\r\n", origin);
+            HttpRequest::parse(&request, &ServerConfig::default()).unwrap()
+        };
+        let cors = |origins: &[&str]| ServerConfig {
+            cors_origin: origins.iter().map(|origin| origin.to_string()).collect(),
+            ..Default::default()
+        };
+        let plain = request("https://a.example");
+
+        assert_eq!(
+            negotiation_headers(&plain, &ServerConfig::default(), Vec::new()),
+            ""
+        );
+        assert_eq!(
+            negotiation_headers(
+                &plain,
+                &ServerConfig::default(),
+                vec!["Accept-Encoding", "Accept", "accept-encoding"]
+            ),
+            "Vary: Accept-Encoding, Accept\r\n"
+        );
+        assert_eq!(
+            negotiation_headers(&plain, &cors(&["*"]), vec!["Accept-Encoding"]),
+            "Access-Control-Allow-Origin: *\r\nVary: Accept-Encoding\r\n"
+        );
+
+        let listed = cors(&["https://A.example", "https://b.example"]);
+        assert_eq!(
+            negotiation_headers(&plain, &listed, vec!["Accept-Encoding"]),
+            "Access-Control-Allow-Origin: https://a.example\r\nVary: Accept-Encoding, Origin\r\n"
+        );
+        assert_eq!(
+            negotiation_headers(&request("https://evil.example"), &listed, vec!["Origin"]),
+            "Vary: Origin\r\n"
+        );
+    }
+
+    #[test]
+    fn method_not_allowed_lists_policy_methods() {
+        let html =
+            HttpRequest::parse("POST /a HTTP/1.1\r\n\r\n", &ServerConfig::default()).unwrap();
+        let json = HttpRequest::parse(
+            "POST /a HTTP/1.1\r\nAccept: application/json\r\n\r\n",
+            &ServerConfig::default(),
+        )
+        .unwrap();
+
+        for writable in [false, true] {
+            let config = ServerConfig {
+                writable,
+                ..Default::default()
+            };
+            let allowed = allowed_methods(&config);
+            let methods: Vec<&str> = allowed.split(", ").collect();
+            assert_eq!(methods.contains(&"PUT"), writable);
+            assert_eq!(methods.contains(&"DELETE"), writable);
+            for method in methods.iter().filter(|&&method| method != "OPTIONS") {
+                assert_ne!(
+                    method_policy(method, &config),
+                    MethodPolicy::NotAllowed,
+                    "{}",
+                    method
+                );
+            }
+
+            let response = format_method_not_allowed(&html, allowed);
+            let (headers, body) = split_response(&response);
+            assert!(headers.starts_with("HTTP/1.1 405 "), "{}", headers);
+            assert!(
+                headers.contains(&format!("\r\nAllow: {}\r\n", allowed)),
+                "{}",
+                headers
+            );
+            let body = String::from_utf8_lossy(body);
+            assert!(
+                body.contains(&format!("Allowed methods: {}", allowed)),
+                "{}",
+                body
+            );
+
+            let response = format_method_not_allowed(&json, allowed);
+            let (headers, body) = split_response(&response);
+            assert!(
+                headers.contains("\r\nContent-Type: application/json\r\n"),
+                "{}",
+                headers
+            );
+            let list: Vec<String> = methods
+                .iter()
+                .map(|method| format!("\"{}\"", method))
+                .collect();
+            let body = String::from_utf8_lossy(body);
+            assert!(
+                body.contains(&format!("\"allowed\":[{}]", list.join(","))),
+                "{}",
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn disallowed_method_status_depends_on_resource() {
+        let root = document_root(
+            "method-resource",
+            &[("file.txt", b"content"), ("dir/inner.txt", b"inner")],
+        );
+        let config = ServerConfig {
+            document_root: root.clone(),
+            ..Default::default()
+        };
+        let post = |path: &str| {
+            let request = format!("POST {} HTTP/1.1\r\nContent-Length: 0\r\n\r\n", path);
+            status_line(&dry_run_response(request.as_bytes(), &config))
+        };
+
+        assert_eq!(post("/file.txt"), "HTTP/1.1 405 Method Not Allowed");
+        assert_eq!(post("/dir"), "HTTP/1.1 405 Method Not Allowed");
+        assert_eq!(post("/missing.txt"), "HTTP/1.1 404 Not Found");
+        assert_eq!(post("/../file.txt"), "HTTP/1.1 403 Forbidden");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn script_and_source_map_content_types() {
+        let config = ServerConfig::default();
+        for name in ["app.js", "module.mjs", "common.cjs", "LEGACY.JS"] {
+            assert_eq!(
+                resolve_content_type(&config, Path::new(name)),
+                "text/javascript; charset=utf-8",
+                "{}",
+                name
+            );
+        }
+        assert_eq!(
+            resolve_content_type(&config, Path::new("app.js.map")),
+            "application/json"
+        );
+        assert_eq!(
+            resolve_content_type(&config, Path::new("data.json")),
+            "application/json"
+        );
+        assert_eq!(
+            resolve_content_type(&config, Path::new("blob")),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn text_types_carry_configured_charset() {
+        let config = ServerConfig::from_args([
+            "static-server",
+            "--default-charset",
+            "Windows-1251",
+            "--charset-ext",
+            ".js=utf-8",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            resolve_content_type(&config, Path::new("index.html")),
+            "text/html; charset=windows-1251"
+        );
+        assert_eq!(
+            resolve_content_type(&config, Path::new("notes.TXT")),
+            "text/plain; charset=windows-1251"
+        );
+        assert_eq!(
+            resolve_content_type(&config, Path::new("app.js")),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(
+            resolve_content_type(&config, Path::new("logo.png")),
+            "image/png"
+        );
+    }
+
+    #[test]
+    fn empty_file_status_follows_empty_as_204() {
+        let root = document_root("empty-as-204", &[("empty.txt", b"")]);
+        let response = |empty_as_204: bool, request: &[u8]| {
+            let config = ServerConfig {
+                document_root: root.clone(),
+                empty_as_204,
+                ..Default::default()
+            };
+            let response = dry_run_response(request, &config);
+            split_response(&response).0
+        };
+        let get = b"GET /empty.txt HTTP/1.1\r\n\r\n";
+
+        let ok = response(false, get);
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"), "{}", ok);
+        assert!(ok.contains("\r\nContent-Length: 0\r\n"), "{}", ok);
+
+        let no_content = response(true, get);
+        assert!(
+            no_content.starts_with("HTTP/1.1 204 No Content\r\n"),
+            "{}",
+            no_content
+        );
+        assert!(!no_content.contains("Content-Length"), "{}", no_content);
+        // HTTP/1.0 без Content-Length ждал бы закрытия соединения
+        let http10 = response(true, b"GET /empty.txt HTTP/1.0\r\n\r\n");
+        assert!(http10.contains("\r\nContent-Length: 0\r\n"), "{}", http10);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn invalid_utf8_in_request_line_is_rejected() {
+        assert_eq!(
+            request_line_utf8_error(b"GET / HTTP/1.1\r\nX-Name: \xff\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            request_line_utf8_error(b"G\xffT / HTTP/1.1\r\n\r\n"),
+            Some(1)
+        );
+        assert_eq!(
+            request_line_utf8_error(b"GET /\xd0 HTTP/1.1\r\n\r\n"),
+            Some(5)
+        );
+        assert_eq!(request_line_utf8_error(b"GET /\xd0\xb0 HTTP/1.1"), None);
+
+        let config = ServerConfig::default();
+        let response = dry_run_response(b"G\xffT / HTTP/1.1\r\n\r\n", &config);
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request");
+        let response = dry_run_response(b"GET / HTTP/1.1\r\nUser-Agent: \xff\r\n\r\n", &config);
+        assert_ne!(status_line(&response), "HTTP/1.1 400 Bad Request");
+    }
+
+    #[test]
+    fn sniffs_types_of_files_without_known_extension() {
+        let mut long_text = "я".repeat(255).into_bytes();
+        long_text.push(0xd1);
+        let root = document_root(
+            "sniff",
+            &[
+                ("logo", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+                ("page", b"  <!DOCTYPE html><html></html>"),
+                ("photo", b"\xff\xd8\xff\xe0"),
+                ("readme", b"plain text"),
+                ("cut", &long_text),
+                ("binary", b"\x7fELF\x02\x01\0"),
+                ("empty", b""),
+            ],
+        );
+        let sniffed = |name: &str| sniff_content_type(&root.join(name));
+
+        assert_eq!(sniffed("logo"), Some("image/png"));
+        assert_eq!(sniffed("page"), Some("text/html"));
+        assert_eq!(sniffed("photo"), Some("image/jpeg"));
+        assert_eq!(sniffed("readme"), Some("text/plain"));
+        assert_eq!(sniffed("cut"), Some("text/plain"));
+        assert_eq!(sniffed("binary"), None);
+        assert_eq!(sniffed("empty"), None);
+
+        let config = |sniff_content_type| ServerConfig {
+            sniff_content_type,
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_content_type(&config(false), &root.join("page")),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            resolve_content_type(&config(true), &root.join("page")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            resolve_content_type(&config(true), &root.join("binary")),
+            "application/octet-stream"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ranges_never_mix_with_compression_on_the_fly() {
+        let text: Vec<u8> = (0..4096).map(|i| b'a' + (i % 26) as u8).collect();
+        let root = document_root("range-gzip", &[("page.txt", &text), ("static.txt", &text)]);
+        let gz = gzip_file(&root.join("static.txt"), 6).unwrap();
+        std::fs::write(root.join("static.txt.gz"), &gz).unwrap();
+        let config = ServerConfig {
+            document_root: root.clone(),
+            gzip: true,
+            gzip_static: true,
+            ..Default::default()
+        };
+        let budget = ConnectionManager::new(TcpListener::bind("127.0.0.1:0").unwrap());
+
+        // Сжатие на лету отключается: байты из исходного файла без Content-Encoding
+        let partial = prepare(
+            "GET /page.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\nRange: bytes=10-19\r\n\r\n",
+            &config,
+            &budget,
+        );
+        let headers = String::from_utf8_lossy(&partial.headers);
+        assert!(headers.starts_with("HTTP/1.1 206 "), "{}", headers);
+        assert!(!headers.contains("Content-Encoding"), "{}", headers);
+        assert!(
+            headers.contains("\r\nContent-Range: bytes 10-19/4096\r\n"),
+            "{}",
+            headers
+        );
+        assert_eq!(partial.file_size, 10);
+        assert_eq!(partial.reserved, 0);
+
+        // Готовый .gz: диапазон считается в байтах сжатого файла
+        let partial = prepare(
+            "GET /static.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\nRange: bytes=0-9\r\n\r\n",
+            &config,
+            &budget,
+        );
+        let headers = String::from_utf8_lossy(&partial.headers);
+        assert!(headers.starts_with("HTTP/1.1 206 "), "{}", headers);
+        assert!(
+            headers.contains("\r\nContent-Encoding: gzip\r\n"),
+            "{}",
+            headers
+        );
+        assert!(
+            headers.contains(&format!("\r\nContent-Range: bytes 0-9/{}\r\n", gz.len())),
+            "{}",
+            headers
+        );
+        let mut body = Vec::new();
+        partial.file.unwrap().read_to_end(&mut body).unwrap();
+        assert_eq!(body[..10], gz[..10]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn head_matches_get_under_compression() {
+        let text: Vec<u8> = (0..4096).map(|i| b'a' + (i % 26) as u8).collect();
+        let root = document_root("head-gzip", &[("page.txt", &text)]);
+        let config = |head_compressed_length| ServerConfig {
+            document_root: root.clone(),
+            gzip: true,
+            head_compressed_length,
+            ..Default::default()
+        };
+        let budget = ConnectionManager::new(TcpListener::bind("127.0.0.1:0").unwrap());
+        let headers = |method: &str, config: &ServerConfig| {
+            let request = format!(
+                "{} /page.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n",
+                method
+            );
+            let response = prepare(&request, config, &budget);
+            let (headers, body) = split_response(&response.headers);
+            if method == "HEAD" {
+                assert!(body.is_empty());
+            }
+            headers
+        };
+
+        let get = headers("GET", &config(false));
+        assert!(get.contains("\r\nContent-Encoding: gzip\r\n"), "{}", get);
+
+        // По умолчанию HEAD описывает несжатое представление
+        let head = headers("HEAD", &config(false));
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(!head.contains("Content-Encoding"), "{}", head);
+        assert!(head.contains("\r\nContent-Length: 4096\r\n"), "{}", head);
+        assert!(head.contains("\r\nVary: Accept-Encoding\r\n"), "{}", head);
+
+        // --head-compressed-length: заголовки HEAD совпадают с GET
+        assert_eq!(headers("HEAD", &config(true)), get);
+        assert_eq!(headers("GET", &config(true)), get);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn error_body_follows_accept_preference() {
+        assert!(prefers_json("application/json"));
+        assert!(prefers_json("text/html;q=0.5, application/json"));
+        assert!(prefers_json("application/*"));
+        assert!(!prefers_json("text/html,application/xhtml+xml,*/*;q=0.8"));
+        assert!(!prefers_json("*/*"));
+        assert!(!prefers_json("application/json;q=0.5, text/html"));
+        assert!(!prefers_json("application/json;q=0"));
+
+        let config = ServerConfig::default();
+        let not_found = |accept: &str| {
+            let request =
+                HttpRequest::parse(&format!("GET /missing HTTP/1.1\r\n{}\r\n", accept), &config)
+                    .unwrap();
+            format_error_response(HttpStatus::NotFound, Some(&request))
+        };
+
+        let browser = not_found("Accept: text/html,*/*;q=0.8\r\n");
+        let (headers, body) = split_response(&browser);
+        assert!(
+            headers.contains("\r\nContent-Type: text/html\r\n"),
+            "{}",
+            headers
+        );
+        assert_eq!(body, b"<html><body><h1>404 Not Found</h1></body></html>");
+
+        let api = not_found("Accept: application/json\r\n");
+        let (headers, body) = split_response(&api);
+        assert!(
+            headers.contains("\r\nContent-Type: application/json\r\n"),
+            "{}",
+            headers
+        );
+        assert!(headers.contains(&format!("\r\nContent-Length: {}\r\n", body.len())));
+        assert_eq!(body, br#"{"error":{"code":404,"message":"Not Found"}}"#);
+
+        assert_eq!(not_found(""), browser);
+        assert_eq!(format_error_response(HttpStatus::NotFound, None), browser);
+    }
+
+    #[test]
+    fn single_file_is_served_for_any_path() {
+        let root = document_root(
+            "single-file",
+            &[
+                ("maintenance.html", b"<h1>back soon</h1>"),
+                ("root/index.html", b"index"),
+            ],
+        );
+        let config = ServerConfig {
+            document_root: root.join("root"),
+            single_file: Some(root.join("maintenance.html")),
+            robots: Some(RobotsPolicy::Deny),
+            ..Default::default()
+        };
+        let budget = ConnectionManager::new(TcpListener::bind("127.0.0.1:0").unwrap());
+
+        for path in [
+            "/",
+            "/index.html",
+            "/missing",
+            "/deep/path/file.txt",
+            "/robots.txt",
+        ] {
+            let request = format!("GET {} HTTP/1.1\r\n\r\n", path);
+            let response = prepare(&request, &config, &budget);
+            let headers = String::from_utf8_lossy(&response.headers);
+            assert!(
+                headers.starts_with("HTTP/1.1 200 OK\r\n"),
+                "{}: {}",
+                path,
+                headers
+            );
+            assert!(
+                headers.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"),
+                "{}: {}",
+                path,
+                headers
+            );
+            let mut body = String::new();
+            response.file.unwrap().read_to_string(&mut body).unwrap();
+            assert_eq!(body, "<h1>back soon</h1>", "{}", path);
+        }
+
+        let head = prepare("HEAD /anything HTTP/1.1\r\n\r\n", &config, &budget);
+        assert!(head.file.is_none());
+        assert!(String::from_utf8_lossy(&head.headers).contains("\r\nContent-Length: 18\r\n"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn root_response_applies_only_without_index() {
+        let root = document_root("root-response", &[("a.txt", b"a"), ("docs/b.txt", b"b")]);
+        let config = |root_response| ServerConfig {
+            document_root: root.clone(),
+            root_response,
+            ..Default::default()
+        };
+        let get_root = |config: &ServerConfig| {
+            let response = dry_run_response(b"GET / HTTP/1.0\r\n\r\n", config);
+            String::from_utf8(response).unwrap()
+        };
+
+        let not_found = get_root(&config(RootResponse::NotFound));
+        assert!(not_found.starts_with("HTTP/1.1 404 "), "{}", not_found);
+
+        let listing = get_root(&config(RootResponse::Autoindex));
+        assert!(listing.starts_with("HTTP/1.1 200 OK\r\n"), "{}", listing);
+        assert!(listing.contains("a.txt"), "{}", listing);
+        assert!(listing.contains("docs/"), "{}", listing);
+
+        let redirect = get_root(&config(RootResponse::Redirect("/docs/".to_string())));
+        assert!(redirect.starts_with("HTTP/1.1 302 "), "{}", redirect);
+        assert!(
+            redirect.contains("\r\nLocation: /docs/\r\n"),
+            "{}",
+            redirect
+        );
+
+        // Только для `/`: остальные пути и корень с index.html не затронуты
+        let config = config(RootResponse::Redirect("/docs/".to_string()));
+        let missing = dry_run_response(b"GET /missing HTTP/1.0\r\n\r\n", &config);
+        assert_eq!(status_line(&missing), "HTTP/1.1 404 Not Found");
+        std::fs::write(root.join("index.html"), "index").unwrap();
+        let index = get_root(&config);
+        assert!(index.starts_with("HTTP/1.1 200 OK\r\n"), "{}", index);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn canonical_host_redirect_keeps_path_and_query() {
+        let config = ServerConfig {
+            canonical_host: Some("example.com".to_string()),
+            ..Default::default()
+        };
+        let get = |request: &str, config: &ServerConfig| {
+            String::from_utf8(dry_run_response(request.as_bytes(), config)).unwrap()
+        };
+
+        let redirect = get(
+            "GET /docs/page.html?lang=ru&v=2 HTTP/1.1\r\nHost: www.example.com\r\n\r\n",
+            &config,
+        );
+        assert!(redirect.starts_with("HTTP/1.1 301 "), "{}", redirect);
+        assert!(
+            redirect.contains("\r\nLocation: http://example.com/docs/page.html?lang=ru&v=2\r\n"),
+            "{}",
+            redirect
+        );
+        let bare = get("GET /a HTTP/1.1\r\nHost: www.example.com\r\n\r\n", &config);
+        assert!(
+            bare.contains("\r\nLocation: http://example.com/a\r\n"),
+            "{}",
+            bare
+        );
+
+        let canonical = get("GET /a?x=1 HTTP/1.1\r\nHost: Example.COM\r\n\r\n", &config);
+        assert!(!canonical.contains("Location"), "{}", canonical);
+
+        // За доверенным прокси хост и схема берутся из Forwarded
+        let proxied = ServerConfig {
+            trust_proxy: true,
+            ..config
+        };
+        let forwarded = get(
+            "GET /a?x=1 HTTP/1.1\r\nHost: backend\r\nForwarded: proto=https;host=www.example.com\r\n\r\n",
+            &proxied,
+        );
+        assert!(
+            forwarded.contains("\r\nLocation: https://example.com/a?x=1\r\n"),
+            "{}",
+            forwarded
+        );
+    }
+
+    #[test]
+    fn image_variant_must_stay_inside_mount_root() {
+        let root = document_root(
+            "variant-mount",
+            &[
+                ("site/hero.webp", b"site webp"),
+                ("images/hero.jpg", b"jpeg"),
+                ("images/logo.png", b"png"),
+                ("images/logo.avif", b"avif"),
+            ],
+        );
+        // Ссылка ведёт в основной корень, но за пределы корня --mount
+        std::os::unix::fs::symlink(root.join("site/hero.webp"), root.join("images/hero.webp"))
+            .unwrap();
+        let config = ServerConfig {
+            document_root: root.join("site"),
+            mount: vec![Mount {
+                prefix: "/img".to_string(),
+                dir: root.join("images"),
+            }],
+            image_variants: true,
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let budget = ConnectionManager::new(TcpListener::bind("127.0.0.1:0").unwrap());
+        let get = |path: &str, accept: &str| {
+            let request = format!("GET {} HTTP/1.1\r\nAccept: {}\r\n\r\n", path, accept);
+            let response = prepare(&request, &config, &budget);
+            let mut body = String::new();
+            response.file.unwrap().read_to_string(&mut body).unwrap();
+            (
+                String::from_utf8_lossy(&response.headers).into_owned(),
+                body,
+            )
+        };
+
+        let (headers, body) = get("/img/logo.png", "image/avif,image/webp,*/*");
+        assert_eq!(body, "avif");
+        assert!(
+            headers.contains("\r\nContent-Type: image/avif\r\n"),
+            "{}",
+            headers
+        );
+        assert!(headers.contains("\r\nVary: Accept\r\n"), "{}", headers);
+        assert_eq!(get("/img/logo.png", "image/webp,*/*").1, "png");
+
+        let (headers, body) = get("/img/hero.jpg", "image/webp,*/*");
+        assert_eq!(body, "jpeg");
+        assert!(
+            headers.contains("\r\nContent-Type: image/jpeg\r\n"),
+            "{}",
+            headers
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn internal_error_body_hides_details_unless_debug_errors() {
+        let path = Path::new("/srv/site/secret \"dir\"/page.html");
+        let error = std::io::Error::other("Input/output error");
+        let failure = |debug_errors: bool, accept: &str, status: HttpStatus| {
+            let config = ServerConfig {
+                debug_errors,
+                ..Default::default()
+            };
+            let request = HttpRequest::parse(
+                &format!("GET /page.html HTTP/1.1\r\nAccept: {}\r\n\r\n", accept),
+                &config,
+            )
+            .unwrap();
+            let response = format_failure_response(status, &request, &config, path, &error);
+            String::from_utf8(split_response(&response).1.to_vec()).unwrap()
+        };
+
+        for accept in ["text/html", "application/json"] {
+            let body = failure(false, accept, HttpStatus::InternalServerError);
+            assert!(!body.contains("/srv"), "{}", body);
+            assert!(!body.contains("Input/output"), "{}", body);
+            // Подробности добавляются только к 500
+            let body = failure(true, accept, HttpStatus::Forbidden);
+            assert!(!body.contains("/srv"), "{}", body);
+        }
+
+        let html = failure(true, "text/html", HttpStatus::InternalServerError);
+        assert!(
+            html.contains(
+                "<pre>/srv/site/secret &quot;dir&quot;/page.html: Input/output error</pre>"
+            ),
+            "{}",
+            html
+        );
+        let json = failure(true, "application/json", HttpStatus::InternalServerError);
+        assert!(
+            json.contains(r#""detail":"/srv/site/secret \"dir\"/page.html: Input/output error""#),
+            "{}",
+            json
+        );
+    }
+
+    #[test]
+    fn not_modified_is_answered_without_opening_the_file() {
+        let root = document_root("not-modified", &[("page.txt", b"cached")]);
+        let config = ServerConfig {
+            document_root: root.clone(),
+            ..Default::default()
+        };
+        let budget = ConnectionManager::new(TcpListener::bind("127.0.0.1:0").unwrap());
+        let full = prepare("GET /page.txt HTTP/1.1\r\n\r\n", &config, &budget);
+        let headers = String::from_utf8_lossy(&full.headers).into_owned();
+        let header = |name: &str| {
+            headers
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .unwrap()
+                .to_string()
+        };
+        assert!(full.file.is_some());
+
+        for condition in [
+            format!("If-None-Match: {}", header("ETag: ")),
+            format!("If-Modified-Since: {}", header("Last-Modified: ")),
+        ] {
+            let request = format!("GET /page.txt HTTP/1.1\r\n{}\r\n\r\n", condition);
+            let cached = prepare(&request, &config, &budget);
+            assert_eq!(status_line(&cached.headers), "HTTP/1.1 304 Not Modified");
+            assert!(cached.file.is_none(), "{}", condition);
+            assert!(cached.segments.is_empty());
         }
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    false
-}
\ No newline at end of file
+    #[test]
+    fn stale_precompressed_file_falls_back_to_source() {
+        let root = document_root(
+            "stale-gz",
+            &[
+                ("app.js", b"console.log('new');"),
+                ("app.js.gz", b"old gzip bytes"),
+            ],
+        );
+        let config = ServerConfig {
+            document_root: root.clone(),
+            gzip_static: true,
+            ..Default::default()
+        };
+        let budget = ConnectionManager::new(TcpListener::bind("127.0.0.1:0").unwrap());
+        let get = || {
+            let request = "GET /app.js HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n";
+            let response = prepare(request, &config, &budget);
+            let mut body = Vec::new();
+            response.file.unwrap().read_to_end(&mut body).unwrap();
+            (
+                String::from_utf8_lossy(&response.headers).into_owned(),
+                body,
+            )
+        };
+        let touch = |name: &str, age: u64| {
+            let modified = std::time::SystemTime::now() - std::time::Duration::from_secs(age);
+            std::fs::File::options()
+                .write(true)
+                .open(root.join(name))
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+
+        touch("app.js", 60);
+        touch("app.js.gz", 3600);
+        let (headers, body) = get();
+        assert!(!headers.contains("Content-Encoding"), "{}", headers);
+        assert_eq!(body, b"console.log('new');");
+
+        touch("app.js.gz", 0);
+        let (headers, body) = get();
+        assert!(
+            headers.contains("\r\nContent-Encoding: gzip\r\n"),
+            "{}",
+            headers
+        );
+        assert_eq!(body, b"old gzip bytes");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
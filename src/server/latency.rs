@@ -0,0 +1,136 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Сколько последних запросов учитывается при расчёте перцентилей
+const SAMPLE_CAPACITY: usize = 1024;
+
+/// Кольцевой буфер длительностей последних завершённых запросов
+struct Samples {
+    durations: Vec<Duration>,
+    next: usize,
+}
+
+static RECENT: Mutex<Samples> = Mutex::new(Samples {
+    durations: Vec::new(),
+    next: 0,
+});
+
+pub struct Percentiles {
+    pub samples: usize,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+impl Percentiles {
+    /// Перцентили в виде JSON-объекта с миллисекундами
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"samples\":{},\"p50_ms\":{:.3},\"p90_ms\":{:.3},\"p99_ms\":{:.3}}}",
+            self.samples,
+            self.p50.as_secs_f64() * 1000.0,
+            self.p90.as_secs_f64() * 1000.0,
+            self.p99.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+impl Samples {
+    fn record(&mut self, duration: Duration) {
+        if self.durations.len() < SAMPLE_CAPACITY {
+            self.durations.push(duration);
+        } else {
+            self.durations[self.next] = duration;
+        }
+        self.next = (self.next + 1) % SAMPLE_CAPACITY;
+    }
+
+    fn percentiles(&self) -> Option<Percentiles> {
+        let mut sorted = self.durations.clone();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_unstable();
+
+        Some(Percentiles {
+            samples: sorted.len(),
+            p50: nearest_rank(&sorted, 50),
+            p90: nearest_rank(&sorted, 90),
+            p99: nearest_rank(&sorted, 99),
+        })
+    }
+}
+
+/// Запоминает длительность завершённого запроса, вытесняя самую старую
+pub fn record(duration: Duration) {
+    RECENT.lock().unwrap().record(duration);
+}
+
+/// p50/p90/p99 по отсортированной копии буфера (None, пока запросов не было)
+pub fn percentiles() -> Option<Percentiles> {
+    RECENT.lock().unwrap().percentiles()
+}
+
+fn nearest_rank(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (percent * sorted.len()).div_ceil(100);
+    sorted[rank.saturating_sub(1)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(value: u64) -> Duration {
+        Duration::from_millis(value)
+    }
+
+    fn samples(durations: impl IntoIterator<Item = u64>) -> Samples {
+        let mut samples = Samples {
+            durations: Vec::new(),
+            next: 0,
+        };
+        for value in durations {
+            samples.record(ms(value));
+        }
+        samples
+    }
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        assert!(samples([]).percentiles().is_none());
+
+        // 1..=100 в обратном порядке: ранг совпадает со значением
+        let percentiles = samples((1..=100).rev()).percentiles().unwrap();
+        assert_eq!(percentiles.samples, 100);
+        assert_eq!(
+            (percentiles.p50, percentiles.p90, percentiles.p99),
+            (ms(50), ms(90), ms(99))
+        );
+
+        let single = samples([7]).percentiles().unwrap();
+        assert_eq!((single.p50, single.p90, single.p99), (ms(7), ms(7), ms(7)));
+
+        let ten = samples(1..=10).percentiles().unwrap();
+        assert_eq!((ten.p50, ten.p90, ten.p99), (ms(5), ms(9), ms(10)));
+    }
+
+    #[test]
+    fn ring_keeps_only_recent_samples() {
+        // 1024 образца по 1000 мс вытесняются значениями 1..=1024, а 5000 -
+        // самым старым из них (1)
+        let old = std::iter::repeat_n(1000, SAMPLE_CAPACITY);
+        let recent = samples(old.chain(1..=SAMPLE_CAPACITY as u64).chain([5000]));
+        assert_eq!(recent.durations.len(), SAMPLE_CAPACITY);
+        assert_eq!(recent.next, 1);
+        assert_eq!(recent.durations[0], ms(5000));
+
+        let percentiles = recent.percentiles().unwrap();
+        assert_eq!(percentiles.samples, SAMPLE_CAPACITY);
+        assert_eq!(percentiles.p50, ms(513));
+        assert_eq!(percentiles.p99, ms(1015));
+        assert_eq!(
+            recent.durations.iter().filter(|&&d| d == ms(1000)).count(),
+            1
+        );
+    }
+}
@@ -1,50 +1,240 @@
+mod autoindex;
+mod compression;
 pub mod config;
 pub mod connection;
 pub mod connection_manager;
+pub mod error;
+mod forwarded;
 mod handlers;
 pub mod http_status;
+mod latency;
+mod range;
+mod request;
+mod trace;
+mod traffic;
+mod upload;
+mod validators;
+mod worker_pool;
 
 use libc::{fd_set, FD_SET, FD_ISSET, FD_ZERO, pselect, timespec};
 use log::{debug, error, info, warn};
-use std::net::TcpListener;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::os::fd::AsRawFd;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread;
-use std::time::Duration;
-use threadpool::ThreadPool;
+use std::time::{Duration, Instant};
 
-use config::ServerConfig;
-use connection_manager::ConnectionManager;
+use config::{ServerConfig, SharedConfig};
+use connection_manager::{ConnectionManager, Rejection};
+use error::ServerError;
 use handlers::{handle_readable_in_pool, handle_writable_in_pool};
+use worker_pool::{Job, WorkerPool};
+
+/// Пауза в приёме соединений после EMFILE/ENFILE
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Не чаще одного предупреждения о нехватке дескрипторов за этот интервал
+const FD_WARNING_INTERVAL: Duration = Duration::from_secs(5);
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Запрошена плавная остановка (--admin-shutdown-path)
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
 
 pub struct HttpServer {
-    config: ServerConfig,
+    config: SharedConfig,
     connection_manager: Arc<ConnectionManager>,
-    thread_pool: ThreadPool,
+    worker_pool: Arc<WorkerPool>,
+    deferred_dispatches: AtomicU64,
+    fd_pressure: Mutex<FdPressure>,
+}
+
+/// Состояние приёма соединений при исчерпании файловых дескрипторов
+#[derive(Default)]
+struct FdPressure {
+    paused_until: Option<Instant>,
+    last_warning: Option<Instant>,
+    suppressed: u64,
 }
 
 impl HttpServer {
-    pub fn new(config: &ServerConfig) -> std::io::Result<Self> {
-        let addr = format!("{}:{}", config.host, config.port);
-        let listener = TcpListener::bind(&addr)?;
+    /// --dry-run: запрос из stdin, ответ в stdout
+    pub fn dry_run(config: &ServerConfig) -> std::io::Result<()> {
+        let mut input = Vec::new();
+        std::io::stdin().read_to_end(&mut input)?;
+
+        let response = handlers::dry_run_response(&input, config);
+        let mut stdout = std::io::stdout();
+        stdout.write_all(&response)?;
+        stdout.flush()
+    }
+
+    pub fn new(config: &ServerConfig) -> Result<Self, ServerError> {
+        Self::prepare_document_root(config)?;
+        if let Some(ref single_file) = config.single_file {
+            if !single_file.is_file() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("--single-file {:?} is not a regular file", single_file),
+                )
+                .into());
+            }
+            info!("Serving {:?} for every request path", single_file);
+        }
+        for mount in &config.mount {
+            if !mount.dir.is_dir() {
+                warn!("Mount {} points to missing directory {:?}", mount.prefix, mount.dir);
+            }
+            info!("Serving {} from {:?}", mount.prefix, mount.dir);
+        }
+        if config.autoindex {
+            autoindex::load_template(config.index_template.as_deref())?;
+        }
+
+        trace::init(config)?;
+
+        let listener = Self::bind_with_retry(config)?;
         listener.set_nonblocking(true)?;
+        if config.tcp_fastopen {
+            enable_fast_open(&listener);
+        }
 
-        info!("Server started on {}", addr);
+        info!("Server started on {}", listener.local_addr()?);
+        Self::report_poller(config);
 
         let connection_manager = Arc::new(ConnectionManager::with_config(listener, config));
-        let thread_pool = ThreadPool::new(config.threads);
+        if connection_manager.fd_limit() != usize::MAX {
+            info!(
+                "Descriptor limit (RLIMIT_NOFILE) {}, refusing new connections within {} of it",
+                connection_manager.fd_limit(),
+                config.fd_headroom
+            );
+        }
+        let worker_pool = Arc::new(WorkerPool::new(config.pool_model, config.threads));
+
+        unsafe {
+            libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t);
+        }
 
         Ok(Self {
-            config: config.clone(),
+            config: SharedConfig::new(config.clone()),
             connection_manager,
-            thread_pool,
+            worker_pool,
+            deferred_dispatches: AtomicU64::new(0),
+            fd_pressure: Mutex::new(FdPressure::default()),
         })
     }
 
+    /// Сводка о механизме ожидания событий. pselect работает только с
+    /// дескрипторами меньше FD_SETSIZE, поэтому близкий к нему
+    /// --max-connections опасен
+    fn report_poller(config: &ServerConfig) {
+        let fd_setsize = libc::FD_SETSIZE;
+        info!(
+            "Poller: pselect (FD_SETSIZE {}), max connections {}, {} worker threads",
+            fd_setsize, config.max_connections, config.threads
+        );
+
+        if config.max_connections + config.fd_headroom > fd_setsize {
+            warn!(
+                "--max-connections {} is too close to FD_SETSIZE {} for pselect: \
+                 descriptors at or above {} cannot be polled; keep it at or below {}",
+                config.max_connections,
+                fd_setsize,
+                fd_setsize,
+                fd_setsize.saturating_sub(config.fd_headroom)
+            );
+        }
+    }
+
+    fn prepare_document_root(config: &ServerConfig) -> std::io::Result<()> {
+        if config.document_root.exists() {
+            return Ok(());
+        }
+
+        if !config.create_root {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "Document root {:?} does not exist and --create-root is disabled",
+                    config.document_root
+                ),
+            ));
+        }
+
+        std::fs::create_dir_all(&config.document_root)?;
+        info!("Created document root {:?}", config.document_root);
+        Ok(())
+    }
+
+    fn bind_with_retry(config: &ServerConfig) -> Result<TcpListener, ServerError> {
+        let mut delay = Duration::from_millis(config.bind_retry_delay);
+        let mut attempt = 0;
+
+        loop {
+            match Self::bind_first_free(config) {
+                Ok(listener) => return Ok(listener),
+                Err(e) if e.is_addr_in_use() && attempt < config.bind_retries => {
+                    attempt += 1;
+                    warn!(
+                        "Bind attempt {}/{} failed: {}, retrying in {:?}",
+                        attempt, config.bind_retries, e, delay
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Перебирает порты из --port-range (или единственный --port) до первого,
+    /// на котором удался bind
+    fn bind_first_free(config: &ServerConfig) -> Result<TcpListener, ServerError> {
+        let ports = config.ports();
+        let (first, last) = (*ports.start(), *ports.end());
+
+        for port in ports {
+            let addr = format!("{}:{}", config.host, port);
+            match TcpListener::bind(&addr) {
+                Ok(listener) => return Ok(listener),
+                Err(e) if port < last => {
+                    debug!("Port {} is unavailable ({}), trying {}", port, e, port + 1);
+                }
+                Err(source) if first == last => return Err(ServerError::Bind { addr, source }),
+                Err(source) => {
+                    return Err(ServerError::Bind {
+                        addr: format!("{}:{}-{}", config.host, first, last),
+                        source,
+                    });
+                }
+            }
+        }
+
+        unreachable!("port range is never empty")
+    }
+
     pub fn run(&self) {
-        info!("Server running with {} threads", self.config.threads);
+        let config = self.config.load();
+        info!(
+            "Server running with {} threads ({:?} pool)",
+            self.worker_pool.threads(),
+            config.pool_model
+        );
 
-        if let Err(e) = self.create_default_files() {
+        if !config.no_default_files
+            && let Err(e) = self.create_default_files()
+        {
             error!("Failed to create default files: {}", e);
         }
 
@@ -52,49 +242,268 @@ impl HttpServer {
 
         let mut total_connections = 0;
         let mut active_connections = 0;
+        let mut last_active = Instant::now();
 
         loop {
-            self.accept_new_connections(&mut total_connections, &mut active_connections);
+            crate::logger::apply_pending_level_change();
+            self.apply_pending_reload();
+
+            let draining = SHUTDOWN_REQUESTED.load(Ordering::SeqCst);
+            if !draining {
+                self.accept_new_connections(&mut total_connections, &mut active_connections);
+            }
             self.handle_ready_connections(listener_fd, &active_connections);
             self.cleanup_closed_connections(&mut active_connections);
+
+            if draining && self.drained() {
+                info!("All connections finished, shutting down");
+                break;
+            }
+            if self.idle_limit_reached(&mut last_active) {
+                break;
+            }
             thread::sleep(Duration::from_millis(1));
         }
+
+        self.worker_pool.join();
+        info!("Server stopped after {} connections", total_connections);
+    }
+
+    /// При остановке закрывает keep-alive соединения между запросами;
+    /// true, когда не осталось ни одного соединения
+    fn drained(&self) -> bool {
+        while self.connection_manager.close_oldest_idle().is_some() {}
+        self.connection_manager.get_connections_count() == 0
+    }
+
+    /// --exit-on-idle: соединений нет дольше заданного времени
+    fn idle_limit_reached(&self, last_active: &mut Instant) -> bool {
+        if self.connection_manager.get_connections_count() > 0 {
+            *last_active = Instant::now();
+            return false;
+        }
+
+        let Some(limit) = self.config.load().exit_on_idle else {
+            return false;
+        };
+
+        let idle = last_active.elapsed();
+        if idle < Duration::from_secs(limit) {
+            return false;
+        }
+
+        info!("No connections for {}s (--exit-on-idle), shutting down", idle.as_secs());
+        true
+    }
+
+    /// Перечитывает --config по SIGHUP и подменяет конфигурацию для новых
+    /// запросов; параметры, требующие перезапуска, остаются прежними
+    fn apply_pending_reload(&self) {
+        if !RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let current = self.config.load();
+        if current.config.is_none() {
+            warn!("SIGHUP received but no --config file is set, nothing to reload");
+            return;
+        }
+
+        let fresh = match ServerConfig::from_args(std::env::args_os()) {
+            Ok(fresh) => fresh,
+            Err(e) => {
+                error!("Failed to reload configuration, keeping the current one: {}", e);
+                return;
+            }
+        };
+
+        for field in current.restart_required_changes(&fresh) {
+            warn!("Config option '{}' changed but requires a restart, skipped", field);
+        }
+
+        self.config.store(current.hot_reloaded(fresh));
+        info!("Configuration reloaded via SIGHUP");
     }
 
+    /// Принимает ожидающие соединения до WouldBlock, но не больше
+    /// --accept-batch за итерацию, чтобы не задерживать уже открытые
     fn accept_new_connections(
         &self,
         total_connections: &mut usize,
         active_connections: &mut usize,
     ) {
+        if self
+            .fd_pressure
+            .lock()
+            .unwrap()
+            .paused_until
+            .is_some_and(|until| Instant::now() < until)
+        {
+            return;
+        }
+
+        for _ in 0..self.config.load().accept_batch {
+            if !self.accept_one(total_connections, active_connections) {
+                break;
+            }
+        }
+    }
+
+    /// Принимает одно соединение; false - продолжать в этой итерации не стоит
+    fn accept_one(&self, total_connections: &mut usize, active_connections: &mut usize) -> bool {
         match self.connection_manager.listener.accept() {
             Ok((stream, addr)) => {
                 debug!("New connection from {}", addr);
+                if stream.as_raw_fd() as usize >= libc::FD_SETSIZE {
+                    warn!(
+                        "Rejecting connection from {}: fd {} is beyond FD_SETSIZE {}",
+                        addr,
+                        stream.as_raw_fd(),
+                        libc::FD_SETSIZE
+                    );
+                    self.connection_manager.record_rejection(Rejection::Descriptor);
+                    return false;
+                }
+
                 if let Err(e) = stream.set_nonblocking(true) {
                     error!("Failed to set non-blocking: {}", e);
-                    return;
+                    return true;
                 }
-
-                if !self.connection_manager.add_connection(stream) {
-                    warn!(
-                        "Maximum connections reached, rejecting connection from {}",
-                        addr
-                    );
-                } else {
-                    *total_connections += 1;
-                    *active_connections += 1;
-                    info!(
-                        "Accepted connection from {} (total: {}, active: {})",
-                        addr, total_connections, active_connections
-                    );
+                self.apply_socket_buffers(&stream);
+                self.apply_socket_timeouts(&stream);
+
+                match self.connection_manager.add_connection(stream) {
+                    Ok(()) => {}
+                    Err(Rejection::Headroom) => {
+                        warn!(
+                            "Within --fd-headroom of the descriptor limit {}, answering 503 to {}",
+                            self.connection_manager.fd_limit(),
+                            addr
+                        );
+                        return false;
+                    }
+                    Err(_) => {
+                        warn!(
+                            "Maximum connections reached, rejecting connection from {}",
+                            addr
+                        );
+                        return false;
+                    }
                 }
+
+                *total_connections += 1;
+                *active_connections += 1;
+                info!(
+                    "Accepted connection from {} (total: {}, active: {})",
+                    addr, total_connections, active_connections
+                );
+                true
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => false,
+            Err(e) if is_transient_accept_error(&e) => {
+                debug!("Skipping aborted connection on accept: {}", e);
+                true
+            }
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) => {
+                self.handle_fd_exhaustion(&e);
+                false
             }
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
             Err(e) => {
                 error!("Error accepting connection: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Выставляет SO_SNDBUF/SO_RCVBUF из конфигурации и логирует значения,
+    /// которые реально применило ядро (Linux удваивает их и ограничивает сверху)
+    fn apply_socket_buffers(&self, stream: &TcpStream) {
+        let config = self.config.load();
+        let fd = stream.as_raw_fd();
+
+        for (option, name, size) in [
+            (libc::SO_SNDBUF, "SO_SNDBUF", config.so_sndbuf),
+            (libc::SO_RCVBUF, "SO_RCVBUF", config.so_rcvbuf),
+        ] {
+            let Some(size) = size else {
+                continue;
+            };
+
+            if let Err(e) = set_socket_option(fd, libc::SOL_SOCKET, option, size as libc::c_int) {
+                warn!("Failed to set {} to {} on fd {}: {}", name, size, fd, e);
+                continue;
+            }
+
+            match get_socket_option(fd, libc::SOL_SOCKET, option) {
+                Ok(effective) => debug!(
+                    "{} on fd {}: requested {}, effective {}",
+                    name, fd, size, effective
+                ),
+                Err(e) => warn!("Failed to read back {} on fd {}: {}", name, fd, e),
+            }
+        }
+    }
+
+    /// SO_RCVTIMEO/SO_SNDTIMEO по --socket-timeouts; нулевой таймаут
+    /// означает отсутствие ограничения и не выставляется
+    fn apply_socket_timeouts(&self, stream: &TcpStream) {
+        let config = self.config.load();
+        if !config.socket_timeouts {
+            return;
+        }
+        let fd = stream.as_raw_fd();
+
+        let timeout = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        if let Err(e) = stream.set_read_timeout(timeout(config.recv_timeout)) {
+            warn!("Failed to set SO_RCVTIMEO on fd {}: {}", fd, e);
+        }
+        if let Err(e) = stream.set_write_timeout(timeout(config.send_timeout)) {
+            warn!("Failed to set SO_SNDTIMEO on fd {}: {}", fd, e);
+        }
+
+        match (stream.read_timeout(), stream.write_timeout()) {
+            (Ok(recv), Ok(send)) => debug!(
+                "Socket timeouts on fd {}: SO_RCVTIMEO {:?}, SO_SNDTIMEO {:?}",
+                fd, recv, send
+            ),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("Failed to read back socket timeouts on fd {}: {}", fd, e)
             }
         }
     }
 
+    /// Нет свободных дескрипторов: освобождаем один за счёт самого старого
+    /// простаивающего keep-alive соединения и ненадолго прекращаем accept,
+    /// чтобы не крутиться в цикле на той же ошибке
+    fn handle_fd_exhaustion(&self, e: &std::io::Error) {
+        let freed = self.connection_manager.close_oldest_idle();
+
+        let mut pressure = self.fd_pressure.lock().unwrap();
+        let now = Instant::now();
+        pressure.paused_until = Some(now + ACCEPT_BACKOFF);
+
+        if pressure
+            .last_warning
+            .is_some_and(|last| now.duration_since(last) < FD_WARNING_INTERVAL)
+        {
+            pressure.suppressed += 1;
+            return;
+        }
+
+        warn!(
+            "Out of file descriptors on accept: {} ({}; pausing accept for {:?}, {} similar warnings suppressed)",
+            e,
+            match freed {
+                Some(fd) => format!("closing idle connection fd {}", fd),
+                None => "no idle connection to close".to_string(),
+            },
+            ACCEPT_BACKOFF,
+            pressure.suppressed
+        );
+        pressure.last_warning = Some(now);
+        pressure.suppressed = 0;
+    }
+
     fn handle_ready_connections(&self, listener_fd: i32, active_connections: &usize) {
         let (read_fds, write_fds) = self.connection_manager.get_connections_for_select();
 
@@ -147,36 +556,67 @@ impl HttpServer {
 
         if ready_count > 0 {
             let mut ready_fds = 0;
+            let mut deferred_fds = 0;
 
             for &fd in &read_fds {
-                if unsafe { FD_ISSET(fd, &mut read_set) } {
+                if unsafe { FD_ISSET(fd, &read_set) } {
+                    if self.pool_saturated(Job::Read) {
+                        deferred_fds += 1;
+                        continue;
+                    }
+
                     let connection_manager = Arc::clone(&self.connection_manager);
-                    let doc_root = self.config.document_root.clone();
-                    let max_file_size = self.config.max_file_size;
-
-                    self.thread_pool.execute(move || {
-                        handle_readable_in_pool(
-                            fd,
-                            connection_manager,
-                            doc_root,
-                            max_file_size,
-                        );
+                    let config = self.config.load();
+                    let worker_pool = Arc::clone(&self.worker_pool);
+
+                    self.connection_manager.mark_in_flight(fd);
+                    self.worker_pool.execute(Job::Read, move || {
+                        handle_readable_in_pool(fd, connection_manager, config, worker_pool);
                     });
                     ready_fds += 1;
                 }
             }
 
             for &fd in &write_fds {
-                if unsafe { FD_ISSET(fd, &mut write_set) } {
+                if unsafe { FD_ISSET(fd, &write_set) } {
+                    if self.pool_saturated(Job::Write) {
+                        deferred_fds += 1;
+                        continue;
+                    }
+
                     let connection_manager = Arc::clone(&self.connection_manager);
+                    let config = self.config.load();
+                    let worker_pool = Arc::clone(&self.worker_pool);
 
-                    self.thread_pool.execute(move || {
-                        handle_writable_in_pool(fd, connection_manager);
+                    self.connection_manager.mark_in_flight(fd);
+                    self.worker_pool.execute(Job::Write, move || {
+                        handle_writable_in_pool(fd, connection_manager, config, worker_pool);
                     });
                     ready_fds += 1;
                 }
             }
 
+            if deferred_fds > 0 {
+                let total = self
+                    .deferred_dispatches
+                    .fetch_add(deferred_fds, Ordering::Relaxed)
+                    + deferred_fds;
+                let groups: Vec<String> = self
+                    .worker_pool
+                    .stats()
+                    .into_iter()
+                    .map(|(name, _, active, queued)| {
+                        format!("{}: {} active, {} queued", name, active, queued)
+                    })
+                    .collect();
+                warn!(
+                    "Thread pool saturated ({}): deferred {} ready connections (total deferred: {})",
+                    groups.join("; "),
+                    deferred_fds,
+                    total
+                );
+            }
+
             if ready_fds > 0 {
                 info!(
                     "pselect found {} ready connections (total: {}, active: {})",
@@ -190,7 +630,20 @@ impl HttpServer {
         }
     }
 
+    /// Все потоки группы заняты и её очередь достигла --max-queued-jobs
+    fn pool_saturated(&self, job: Job) -> bool {
+        self.worker_pool.saturated(job, self.config.load().max_queued_jobs)
+    }
+
     fn cleanup_closed_connections(&self, active_connections: &mut usize) {
+        let config = self.config.load();
+        self.connection_manager.close_idle_connections(
+            Duration::from_secs(config.keepalive_timeout),
+            Duration::from_secs(config.recv_timeout),
+            Duration::from_secs(config.send_timeout),
+            config.max_connection_age.map(Duration::from_secs),
+        );
+
         let closed_fds = self.connection_manager.get_closed_connections();
         for fd in closed_fds {
             if let Some(conn) = self.connection_manager.remove_connection(fd) {
@@ -214,20 +667,128 @@ impl HttpServer {
         use crate::static_files::{css_content, html_content};
         use std::fs;
 
-        let index_path = self.config.document_root.join("index.html");
-        let css_path = self.config.document_root.join("style.css");
+        let config = self.config.load();
+        let document_root = &config.document_root;
+        let index_path = document_root.join("index.html");
+        let css_path = document_root.join("style.css");
 
-        if !self.config.document_root.exists() {
-            fs::create_dir_all(&self.config.document_root)?;
+        if config.blank_default {
+            fs::write(index_path, html_content::get_blank_html())?;
+            info!("Created blank default page in {:?}", document_root);
+            return Ok(());
         }
 
         fs::write(index_path, html_content::get_html())?;
         fs::write(css_path, css_content::get_css())?;
 
-        info!(
-            "Created default chess-themed page in {:?}",
-            self.config.document_root
-        );
+        info!("Created default chess-themed page in {:?}", document_root);
         Ok(())
     }
 }
+
+fn set_socket_option(
+    fd: i32,
+    level: libc::c_int,
+    option: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn get_socket_option(fd: i32, level: libc::c_int, option: libc::c_int) -> std::io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            option,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// Длина очереди соединений TCP Fast Open, ожидающих завершения рукопожатия
+const FAST_OPEN_QUEUE: libc::c_int = 256;
+
+/// TCP_FASTOPEN на слушающем сокете. Серверная часть включается битом 0x2
+/// в net.ipv4.tcp_fastopen; без него или при ошибке сервер работает с
+/// обычным accept
+#[cfg(target_os = "linux")]
+fn enable_fast_open(listener: &TcpListener) {
+    let sysctl = std::fs::read_to_string("/proc/sys/net/ipv4/tcp_fastopen")
+        .ok()
+        .and_then(|value| value.trim().parse::<u32>().ok());
+    if sysctl.is_some_and(|flags| flags & 0x2 == 0) {
+        warn!("TCP Fast Open is disabled for servers by net.ipv4.tcp_fastopen, enable bit 0x2");
+    }
+
+    let fd = listener.as_raw_fd();
+    match set_socket_option(fd, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, FAST_OPEN_QUEUE)
+        .and_then(|_| get_socket_option(fd, libc::IPPROTO_TCP, libc::TCP_FASTOPEN))
+    {
+        Ok(queue) => info!("TCP_FASTOPEN set on the listener (queue {})", queue),
+        Err(e) => warn!("Failed to enable TCP Fast Open, using plain accept: {}", e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_fast_open(_listener: &TcpListener) {
+    warn!("--tcp-fastopen is only supported on Linux, using plain accept");
+}
+
+/// Ошибки accept, относящиеся к одному соединению (клиент успел сбросить
+/// его, пока оно ждало в очереди, или вызов прерван сигналом): слушающий
+/// сокет исправен, и можно принимать следующие
+fn is_transient_accept_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::Interrupted
+    ) || e.raw_os_error() == Some(libc::EPROTO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Error;
+
+    #[test]
+    fn aborted_and_interrupted_accepts_are_transient() {
+        for errno in [libc::ECONNABORTED, libc::EINTR, libc::EPROTO] {
+            assert!(
+                is_transient_accept_error(&Error::from_raw_os_error(errno)),
+                "errno {}",
+                errno
+            );
+        }
+        for errno in [libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::EBADF] {
+            assert!(
+                !is_transient_accept_error(&Error::from_raw_os_error(errno)),
+                "errno {}",
+                errno
+            );
+        }
+        assert!(!is_transient_accept_error(&Error::from(
+            std::io::ErrorKind::WouldBlock
+        )));
+    }
+}
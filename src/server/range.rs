@@ -0,0 +1,164 @@
+#[derive(Debug, Clone, PartialEq)]
+pub enum RangeRequest {
+    /// Заголовок отсутствует, некорректен или не поддерживается - отдаём файл целиком
+    Full,
+    /// Включительный диапазон байт [start, end]
+    Partial(u64, u64),
+    /// Несколько включительных диапазонов, отдаются как multipart/byteranges
+    Multi(Vec<(u64, u64)>),
+    /// Все диапазоны за пределами файла
+    Unsatisfiable,
+}
+
+enum ByteRangeSpec {
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+    Invalid,
+}
+
+/// Разбирает заголовок Range. Если диапазонов больше `max_ranges` или
+/// их суммарный объём превышает размер файла (сильное перекрытие),
+/// запрос обслуживается целиком, чтобы избежать усиления ответа.
+pub fn parse_range(header: &str, file_size: u64, max_ranges: usize) -> RangeRequest {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return RangeRequest::Full,
+    };
+
+    let specs: Vec<&str> = specs.split(',').map(str::trim).collect();
+    if specs.len() > max_ranges {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs {
+        match parse_spec(spec, file_size) {
+            ByteRangeSpec::Satisfiable(start, end) => ranges.push((start, end)),
+            ByteRangeSpec::Unsatisfiable => {}
+            ByteRangeSpec::Invalid => return RangeRequest::Full,
+        }
+    }
+
+    match ranges.len() {
+        0 => RangeRequest::Unsatisfiable,
+        1 => RangeRequest::Partial(ranges[0].0, ranges[0].1),
+        _ => {
+            let total: u64 = ranges.iter().map(|(start, end)| end - start + 1).sum();
+            if total > file_size {
+                RangeRequest::Full
+            } else {
+                RangeRequest::Multi(ranges)
+            }
+        }
+    }
+}
+
+fn parse_spec(spec: &str, file_size: u64) -> ByteRangeSpec {
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRangeSpec::Invalid,
+    };
+
+    if start.is_empty() {
+        let suffix_len: u64 = match end.parse() {
+            Ok(len) => len,
+            Err(_) => return ByteRangeSpec::Invalid,
+        };
+        if suffix_len == 0 || file_size == 0 {
+            return ByteRangeSpec::Unsatisfiable;
+        }
+        return ByteRangeSpec::Satisfiable(file_size - suffix_len.min(file_size), file_size - 1);
+    }
+
+    let start: u64 = match start.parse() {
+        Ok(start) => start,
+        Err(_) => return ByteRangeSpec::Invalid,
+    };
+
+    let end: u64 = if end.is_empty() {
+        u64::MAX
+    } else {
+        match end.parse() {
+            Ok(end) => end,
+            Err(_) => return ByteRangeSpec::Invalid,
+        }
+    };
+
+    if start > end {
+        return ByteRangeSpec::Invalid;
+    }
+
+    if start >= file_size {
+        return ByteRangeSpec::Unsatisfiable;
+    }
+
+    ByteRangeSpec::Satisfiable(start, end.min(file_size - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-9", 100, 8),
+            RangeRequest::Partial(0, 9)
+        );
+        assert_eq!(
+            parse_range("bytes=90-", 100, 8),
+            RangeRequest::Partial(90, 99)
+        );
+        assert_eq!(
+            parse_range("bytes=-10", 100, 8),
+            RangeRequest::Partial(90, 99)
+        );
+        // Конец за пределами файла обрезается по размеру
+        assert_eq!(
+            parse_range("bytes=50-1000", 100, 8),
+            RangeRequest::Partial(50, 99)
+        );
+        assert_eq!(
+            parse_range("bytes=-1000", 100, 8),
+            RangeRequest::Partial(0, 99)
+        );
+    }
+
+    #[test]
+    fn out_of_bounds_range_is_unsatisfiable() {
+        assert_eq!(
+            parse_range("bytes=9999-10000", 100, 8),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range("bytes=100-", 100, 8),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=-0", 100, 8), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-5", 0, 8), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn invalid_header_serves_full_file() {
+        assert_eq!(parse_range("items=0-9", 100, 8), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=9-0", 100, 8), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=abc", 100, 8), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=0-9,x-1", 100, 8), RangeRequest::Full);
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-9, 20-29, 9999-", 100, 8),
+            RangeRequest::Multi(vec![(0, 9), (20, 29)])
+        );
+    }
+
+    #[test]
+    fn amplifying_ranges_serve_full_file() {
+        // Больше --max-ranges диапазонов
+        assert_eq!(parse_range("bytes=0-0,2-2,4-4", 100, 2), RangeRequest::Full);
+        // Перекрывающиеся диапазоны в сумме больше файла
+        assert_eq!(parse_range("bytes=0-79,10-89", 100, 8), RangeRequest::Full);
+    }
+}
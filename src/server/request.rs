@@ -0,0 +1,396 @@
+use log::warn;
+use std::time::{Duration, Instant};
+
+use super::config::ServerConfig;
+use super::http_status::HttpStatus;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestTarget<'a> {
+    /// `/path?query` - путь без строки запроса
+    Origin(&'a str),
+    /// `http://host/path` - путь, извлечённый из абсолютного URI
+    Absolute(&'a str),
+    /// `host:port` - допустим только для CONNECT
+    Authority,
+    /// `*` - допустим только для OPTIONS
+    Asterisk,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpRequest {
+    pub fn parse(request_str: &str, config: &ServerConfig) -> Result<Self, HttpStatus> {
+        let deadline = Instant::now() + Duration::from_millis(config.max_parse_time);
+        Self::parse_until(request_str, config, deadline)
+    }
+
+    /// Разбор с явным сроком окончания бюджета --max-parse-time
+    fn parse_until(
+        request_str: &str,
+        config: &ServerConfig,
+        deadline: Instant,
+    ) -> Result<Self, HttpStatus> {
+        let over_budget = || {
+            let exceeded = Instant::now() > deadline;
+            if exceeded {
+                warn!(
+                    "Parsing a {} byte request exceeded --max-parse-time {}ms",
+                    request_str.len(),
+                    config.max_parse_time
+                );
+            }
+            exceeded
+        };
+
+        if config.strict_crlf && has_bare_lf(request_str) {
+            return Err(HttpStatus::BadRequest);
+        }
+
+        let mut lines = request_str.lines();
+
+        let first_line: Vec<&str> = match lines.next() {
+            Some(line) => line.split_whitespace().collect(),
+            None => return Err(HttpStatus::BadRequest),
+        };
+        if first_line.len() < 2 {
+            return Err(HttpStatus::BadRequest);
+        }
+
+        // Бюджет проверяется и вокруг разбиения на строки: на огромном
+        // заголовке время уходит ещё до разбора отдельных полей
+        if over_budget() {
+            return Err(HttpStatus::BadRequest);
+        }
+        let header_lines: Vec<&str> = lines.take_while(|line| !line.is_empty()).collect();
+        if over_budget() {
+            return Err(HttpStatus::BadRequest);
+        }
+        if header_lines.len() > config.max_headers {
+            return Err(HttpStatus::RequestHeaderFieldsTooLarge);
+        }
+
+        let mut headers: Vec<(String, String)> = Vec::new();
+        for line in header_lines {
+            if over_budget() {
+                return Err(HttpStatus::BadRequest);
+            }
+
+            // obs-fold: строка-продолжение, начинающаяся с пробела или табуляции
+            if line.starts_with([' ', '\t']) {
+                let Some((_, value)) = headers.last_mut().filter(|_| config.allow_obs_fold) else {
+                    return Err(HttpStatus::BadRequest);
+                };
+                let continuation = trim_ows(line);
+                if !continuation.is_empty() {
+                    if !value.is_empty() {
+                        value.push(' ');
+                    }
+                    value.push_str(continuation);
+                }
+                continue;
+            }
+
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_string(), trim_ows(value).to_string()));
+            }
+        }
+
+        Ok(Self {
+            method: first_line[0].to_string(),
+            target: first_line[1].to_string(),
+            version: first_line.get(2).unwrap_or(&"HTTP/1.0").to_string(),
+            headers,
+        })
+    }
+
+    pub fn target_form(&self) -> RequestTarget<'_> {
+        let target = self.target.as_str();
+
+        if target == "*" {
+            return RequestTarget::Asterisk;
+        }
+
+        if target.starts_with('/') {
+            return RequestTarget::Origin(strip_query(target));
+        }
+
+        match target.split_once("://") {
+            Some((_, rest)) => match rest.find('/') {
+                Some(start) => RequestTarget::Absolute(strip_query(&rest[start..])),
+                None => RequestTarget::Absolute("/"),
+            },
+            None => RequestTarget::Authority,
+        }
+    }
+
+    pub fn is_http10(&self) -> bool {
+        self.version == "HTTP/1.0"
+    }
+
+    /// Клиент HTTP/1.1 ждёт `100 Continue`, прежде чем отправлять тело
+    pub fn expects_continue(&self) -> bool {
+        !self.is_http10()
+            && self
+                .header("Expect")
+                .is_some_and(|value| value.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    pub fn wants_keep_alive(&self) -> bool {
+        let connection = self.header("Connection").map(str::to_ascii_lowercase);
+        match connection.as_deref() {
+            Some(value) if value.contains("close") => false,
+            Some(value) if value.contains("keep-alive") => true,
+            _ => !self.is_http10(),
+        }
+    }
+
+    /// Несколько различающихся Content-Length или Content-Length вместе с
+    /// Transfer-Encoding: chunked - классический вектор request smuggling.
+    pub fn has_conflicting_length(&self) -> bool {
+        let lengths: Vec<&str> = self
+            .headers_all("Content-Length")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+
+        if lengths.is_empty() {
+            return false;
+        }
+
+        if lengths.iter().any(|len| len.parse::<u64>().is_err())
+            || lengths.iter().any(|len| *len != lengths[0])
+        {
+            return true;
+        }
+
+        self.headers_all("Transfer-Encoding")
+            .any(|te| te.to_ascii_lowercase().contains("chunked"))
+    }
+
+    pub fn headers_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn has_bare_lf(request_str: &str) -> bool {
+    let head = match request_str.find("\r\n\r\n") {
+        Some(end) => &request_str.as_bytes()[..end + 4],
+        None => request_str.as_bytes(),
+    };
+
+    head.iter()
+        .enumerate()
+        .any(|(i, &byte)| byte == b'\n' && (i == 0 || head[i - 1] != b'\r'))
+}
+
+/// Убирает необязательные пробелы (OWS: SP и HTAB) вокруг значения заголовка
+fn trim_ows(value: &str) -> &str {
+    value.trim_matches([' ', '\t'])
+}
+
+fn strip_query(target: &str) -> &str {
+    target.split(['?', '#']).next().unwrap_or(target)
+}
+
+/// Декодирует percent-кодирование пути и нормализует его: повторные `/` и
+/// сегменты `.` убираются, `..` остаётся (его отвергает обработчик).
+/// Некорректная последовательность, `%00` или не-UTF-8 дают 400
+pub fn normalize_path(path: &str) -> Result<String, HttpStatus> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let byte = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match byte {
+            Some(0) | None => return Err(HttpStatus::BadRequest),
+            Some(byte) => decoded.push(byte),
+        }
+        i += 3;
+    }
+    let decoded = String::from_utf8(decoded).map_err(|_| HttpStatus::BadRequest)?;
+
+    let mut normalized = String::with_capacity(decoded.len());
+    for segment in decoded.split('/').filter(|s| !s.is_empty() && *s != ".") {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() || decoded.ends_with('/') {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Элементы списка с весами (Accept, Accept-Encoding): имя и значение `q`,
+/// по умолчанию 1
+pub fn weighted_items(value: &str) -> impl Iterator<Item = (&str, f32)> {
+    value.split(',').map(|item| {
+        let mut parts = item.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let quality = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        (name, quality)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(request: &str) -> Result<HttpRequest, HttpStatus> {
+        HttpRequest::parse(request, &ServerConfig::default())
+    }
+
+    #[test]
+    fn parses_request_line_and_headers() {
+        let request =
+            parse("GET /index.html?x=1 HTTP/1.1\r\nHost: example\r\nX-Pad:  a b \t\r\n\r\n")
+                .unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.target, "/index.html?x=1");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.header("host"), Some("example"));
+        assert_eq!(request.header("X-Pad"), Some("a b"));
+        assert_eq!(request.target_form(), RequestTarget::Origin("/index.html"));
+    }
+
+    #[test]
+    fn rejects_incomplete_request_line() {
+        assert!(matches!(parse("GET\r\n\r\n"), Err(HttpStatus::BadRequest)));
+        assert!(matches!(parse(""), Err(HttpStatus::BadRequest)));
+    }
+
+    #[test]
+    fn conflicting_lengths() {
+        let conflicting = |headers: &str| {
+            parse(&format!("POST /upload HTTP/1.1\r\n{}\r\n", headers))
+                .unwrap()
+                .has_conflicting_length()
+        };
+        assert!(!conflicting("Content-Length: 5\r\n"));
+        assert!(!conflicting("Content-Length: 5\r\nContent-Length: 5\r\n"));
+        assert!(conflicting("Content-Length: 5\r\nContent-Length: 6\r\n"));
+        assert!(conflicting("Content-Length: 5, 6\r\n"));
+        assert!(conflicting("Content-Length: five\r\n"));
+        assert!(conflicting(
+            "Content-Length: 5\r\nTransfer-Encoding: chunked\r\n"
+        ));
+        assert!(!conflicting("Transfer-Encoding: chunked\r\n"));
+    }
+
+    #[test]
+    fn strict_crlf() {
+        let bare_lf = "GET / HTTP/1.1\nHost: example\n\n";
+        let crlf = "GET / HTTP/1.1\r\nHost: example\r\n\r\n";
+        let strict = ServerConfig {
+            strict_crlf: true,
+            ..ServerConfig::default()
+        };
+
+        assert!(HttpRequest::parse(crlf, &strict).is_ok());
+        assert!(matches!(
+            HttpRequest::parse(bare_lf, &strict),
+            Err(HttpStatus::BadRequest)
+        ));
+        assert_eq!(parse(bare_lf).unwrap().header("Host"), Some("example"));
+    }
+
+    #[test]
+    fn obs_fold() {
+        let folded = "GET / HTTP/1.1\r\nX-Long: first\r\n \t second\r\n\r\n";
+        let allow = ServerConfig {
+            allow_obs_fold: true,
+            ..ServerConfig::default()
+        };
+
+        assert!(matches!(parse(folded), Err(HttpStatus::BadRequest)));
+        let request = HttpRequest::parse(folded, &allow).unwrap();
+        assert_eq!(request.header("X-Long"), Some("first second"));
+        // Продолжение без предшествующего заголовка некорректно и с флагом
+        assert!(matches!(
+            HttpRequest::parse("GET / HTTP/1.1\r\n folded\r\n\r\n", &allow),
+            Err(HttpStatus::BadRequest)
+        ));
+    }
+
+    #[test]
+    fn max_headers() {
+        let config = ServerConfig {
+            max_headers: 3,
+            ..ServerConfig::default()
+        };
+        let request = |count: usize| {
+            let headers: String = (0..count).map(|i| format!("X-{}: {}\r\n", i, i)).collect();
+            format!("GET / HTTP/1.1\r\n{}\r\n", headers)
+        };
+
+        assert!(HttpRequest::parse(&request(3), &config).is_ok());
+        assert!(matches!(
+            HttpRequest::parse(&request(4), &config),
+            Err(HttpStatus::RequestHeaderFieldsTooLarge)
+        ));
+    }
+
+    #[test]
+    fn expired_parse_budget_rejects_request() {
+        let config = ServerConfig::default();
+        let request = "GET / HTTP/1.1\r\nHost: example\r\n\r\n";
+        let expired = Instant::now() - Duration::from_millis(1);
+        let distant = Instant::now() + Duration::from_secs(3600);
+
+        assert!(matches!(
+            HttpRequest::parse_until(request, &config, expired),
+            Err(HttpStatus::BadRequest)
+        ));
+        assert!(HttpRequest::parse_until(request, &config, distant).is_ok());
+    }
+
+    #[test]
+    fn weighted_items_default_to_full_quality() {
+        let items: Vec<(&str, f32)> = weighted_items("gzip;q=0.5, br, identity; q=0").collect();
+        assert_eq!(items, vec![("gzip", 0.5), ("br", 1.0), ("identity", 0.0)]);
+    }
+
+    #[test]
+    fn normalize_path_decodes_and_collapses() {
+        assert_eq!(normalize_path("/").unwrap(), "/");
+        assert_eq!(normalize_path("//").unwrap(), "/");
+        assert_eq!(normalize_path("/a%20b.txt").unwrap(), "/a b.txt");
+        assert_eq!(normalize_path("/dir//./%41/").unwrap(), "/dir/A/");
+        assert_eq!(normalize_path("/%2e%2e/etc").unwrap(), "/../etc");
+        assert_eq!(normalize_path("/%D0%B0").unwrap(), "/\u{430}");
+        for malformed in ["/%", "/%4", "/%zz", "/%+1", "/%00", "/%ff"] {
+            assert_eq!(
+                normalize_path(malformed),
+                Err(HttpStatus::BadRequest),
+                "{}",
+                malformed
+            );
+        }
+    }
+}
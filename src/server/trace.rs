@@ -0,0 +1,129 @@
+use chrono_tz::Europe::Moscow;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::fd::RawFd;
+use std::sync::Mutex;
+
+use super::config::ServerConfig;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Recv,
+    Send,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::Recv => "recv",
+            Direction::Send => "send",
+        }
+    }
+}
+
+/// Файл трассировки. Записи одного fd чередуются по направлениям:
+/// смена направления означает начало следующего запроса или ответа
+struct Tracer {
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_bytes: u64,
+    messages: HashMap<RawFd, (Direction, u64)>,
+    full: bool,
+}
+
+static TRACER: Mutex<Option<Tracer>> = Mutex::new(None);
+
+/// Открывает --trace-file; без него record ничего не делает
+pub fn init(config: &ServerConfig) -> std::io::Result<()> {
+    let Some(ref path) = config.trace_file else {
+        return Ok(());
+    };
+
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    info!("Tracing raw requests and responses to {:?}", path);
+
+    *TRACER.lock().unwrap() = Some(Tracer {
+        file,
+        size,
+        max_size: config.trace_max_size,
+        max_bytes: config.trace_max_bytes,
+        messages: HashMap::new(),
+        full: false,
+    });
+    Ok(())
+}
+
+/// Дописывает прочитанные или отправленные байты соединения. Запись
+/// делается под общей блокировкой, так что записи разных потоков не
+/// перемешиваются
+pub fn record(fd: RawFd, direction: Direction, data: &[u8]) {
+    let mut tracer = TRACER.lock().unwrap();
+    let Some(tracer) = tracer.as_mut() else {
+        return;
+    };
+    if tracer.full || data.is_empty() {
+        return;
+    }
+
+    let traced = match tracer.messages.get(&fd) {
+        Some(&(last, traced)) if last == direction => traced,
+        _ => 0,
+    };
+    tracer.messages.insert(fd, (direction, traced + data.len() as u64));
+
+    let kept = if tracer.max_bytes == 0 {
+        data.len()
+    } else {
+        tracer.max_bytes.saturating_sub(traced).min(data.len() as u64) as usize
+    };
+    if kept == 0 {
+        return;
+    }
+
+    let timestamp = chrono::Utc::now()
+        .with_timezone(&Moscow)
+        .format("%Y-%m-%dT%H:%M:%S%.6fZ%:z");
+    let mut frame = format!(
+        "=== {} fd {} {} {} bytes",
+        timestamp,
+        fd,
+        direction.as_str(),
+        data.len()
+    );
+    if kept < data.len() {
+        frame.push_str(&format!(" (truncated to {})", kept));
+    }
+    frame.push('\n');
+
+    let mut entry = frame.into_bytes();
+    entry.extend_from_slice(&data[..kept]);
+    entry.push(b'\n');
+
+    if tracer.max_size > 0 && tracer.size + entry.len() as u64 > tracer.max_size {
+        warn!(
+            "Trace file reached --trace-max-size {} bytes, tracing stopped",
+            tracer.max_size
+        );
+        tracer.full = true;
+        return;
+    }
+
+    match tracer.file.write_all(&entry) {
+        Ok(()) => tracer.size += entry.len() as u64,
+        Err(e) => {
+            error!("Error writing trace file, tracing stopped: {}", e);
+            tracer.full = true;
+        }
+    }
+}
+
+/// Забывает состояние закрытого соединения, чтобы его fd начал новую запись
+pub fn forget(fd: RawFd) {
+    if let Some(tracer) = TRACER.lock().unwrap().as_mut() {
+        tracer.messages.remove(&fd);
+    }
+}
@@ -0,0 +1,88 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Грубая категория ответа по Content-Type: несколько фиксированных
+/// значений, чтобы число счётчиков не зависело от типов файлов
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Category {
+    Html,
+    Image,
+    Script,
+    #[default]
+    Other,
+}
+
+static SENT_BYTES: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+impl Category {
+    const ALL: [Category; 4] = [
+        Category::Html,
+        Category::Image,
+        Category::Script,
+        Category::Other,
+    ];
+
+    /// Категория по заголовку Content-Type готового ответа
+    pub fn from_response(headers: &[u8]) -> Self {
+        let header_end = headers
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .unwrap_or(headers.len());
+
+        String::from_utf8_lossy(&headers[..header_end])
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Type"))
+            .map(|(_, value)| Self::from_content_type(value.trim()))
+            .unwrap_or_default()
+    }
+
+    fn from_content_type(content_type: &str) -> Self {
+        let media = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        match media.as_str() {
+            "text/html" | "application/xhtml+xml" => Category::Html,
+            "text/javascript" | "application/javascript" | "application/wasm" => Category::Script,
+            _ if media.starts_with("image/") => Category::Image,
+            _ => Category::Other,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Category::Html => "html",
+            Category::Image => "image",
+            Category::Script => "script",
+            Category::Other => "other",
+        }
+    }
+}
+
+/// Учитывает отправленные клиенту байты (заголовки и тело)
+pub fn record(category: Category, bytes: usize) {
+    SENT_BYTES[category as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// Отправленные байты по категориям в виде JSON-объекта
+pub fn to_json() -> String {
+    let counters: Vec<String> = Category::ALL
+        .iter()
+        .map(|&category| {
+            format!(
+                "\"{}\":{}",
+                category.name(),
+                SENT_BYTES[category as usize].load(Ordering::Relaxed)
+            )
+        })
+        .collect();
+    format!("{{{}}}", counters.join(","))
+}
@@ -0,0 +1,92 @@
+use log::{debug, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Загрузка тела PUT во временный файл с атомарным переименованием в цель.
+/// Если загрузка не была завершена через `commit`, временный файл удаляется.
+#[derive(Debug)]
+pub struct Upload {
+    file: File,
+    temp_path: PathBuf,
+    target_path: PathBuf,
+    pub expected: u64,
+    pub received: u64,
+    committed: bool,
+}
+
+impl Upload {
+    pub fn begin(target_path: &Path, tmp_dir: Option<&Path>, expected: u64) -> std::io::Result<Self> {
+        let tmp_dir = match tmp_dir {
+            Some(dir) => dir.to_path_buf(),
+            None => target_path.parent().unwrap_or(Path::new(".")).to_path_buf(),
+        };
+
+        let file_name = target_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+
+        let temp_path = tmp_dir.join(format!(
+            ".{}.upload-{}-{:x}",
+            file_name,
+            std::process::id(),
+            nanos
+        ));
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+
+        debug!("Started upload of {:?} via {:?}", target_path, temp_path);
+
+        Ok(Self {
+            file,
+            temp_path,
+            target_path: target_path.to_path_buf(),
+            expected,
+            received: 0,
+            committed: false,
+        })
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.expected - self.received
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received >= self.expected
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(data)?;
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> std::io::Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.temp_path, &self.target_path)?;
+        self.committed = true;
+        debug!("Upload committed to {:?}", self.target_path);
+        Ok(())
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if !self.committed {
+            if let Err(e) = fs::remove_file(&self.temp_path) {
+                warn!("Failed to remove upload temp file {:?}: {}", self.temp_path, e);
+            } else {
+                debug!("Removed incomplete upload {:?}", self.temp_path);
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Сколько хешей содержимого хранится для --etag strong
+const HASH_CACHE_CAPACITY: usize = 4096;
+
+/// Хеш содержимого вместе с mtime и размером, при которых он вычислен
+type CachedHash = (SystemTime, u64, String);
+
+/// Хеши содержимого по пути; запись действительна, пока не изменились
+/// mtime и размер файла
+static HASH_CACHE: LazyLock<Mutex<HashCache>> = LazyLock::new(|| Mutex::new(HashCache::default()));
+
+/// Кеш хешей с вытеснением самой старой (по времени добавления) записи
+#[derive(Default)]
+struct HashCache {
+    entries: HashMap<PathBuf, CachedHash>,
+    order: VecDeque<PathBuf>,
+}
+
+impl HashCache {
+    fn get(&self, path: &Path) -> Option<&CachedHash> {
+        self.entries.get(path)
+    }
+
+    fn insert(&mut self, path: PathBuf, hash: CachedHash) {
+        if let Some(entry) = self.entries.get_mut(&path) {
+            *entry = hash;
+            return;
+        }
+        if self.entries.len() >= HASH_CACHE_CAPACITY
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.entries.remove(&oldest);
+        }
+        self.order.push_back(path.clone());
+        self.entries.insert(path, hash);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: String,
+}
+
+impl Validators {
+    /// Валидаторы по метаданным. ETag слабый: mtime с точностью до секунды и
+    /// размер не гарантируют побайтного совпадения содержимого
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let mtime = modified
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        Self {
+            etag: format!("W/\"{:x}-{:x}\"", mtime, metadata.len()),
+            last_modified: format_http_date(modified),
+        }
+    }
+
+    /// Как `from_metadata`, но ETag строгий - хеш SHA-256 содержимого: одинаковые
+    /// файлы получают одинаковый ETag на любых серверах независимо от mtime
+    pub fn strong_for_file(path: &Path, metadata: &Metadata) -> std::io::Result<Self> {
+        let mut validators = Self::from_metadata(metadata);
+        validators.etag = content_etag(path, metadata)?;
+        Ok(validators)
+    }
+
+    /// ETag сжатого gzip представления того же файла
+    pub fn gzip_etag(&self) -> String {
+        format!("{}-gzip\"", self.etag.trim_end_matches('"'))
+    }
+
+    /// Условный GET/HEAD: If-None-Match (слабое сравнение) имеет приоритет
+    /// над If-Modified-Since
+    pub fn not_modified(&self, if_none_match: Option<&str>, if_modified_since: Option<&str>) -> bool {
+        if let Some(if_none_match) = if_none_match {
+            let etag = opaque_tag(&self.etag);
+            let gzip_etag = self.gzip_etag();
+            let gzip_etag = opaque_tag(&gzip_etag);
+            return if_none_match.split(',').map(str::trim).any(|tag| {
+                let tag = opaque_tag(tag);
+                tag == "*" || tag == etag || tag == gzip_etag
+            });
+        }
+
+        match (if_modified_since.and_then(parse_http_date), parse_http_date(&self.last_modified)) {
+            (Some(since), Some(current)) => current <= since,
+            _ => false,
+        }
+    }
+
+    /// Проверяет If-Range: диапазон отдаётся только если валидатор совпадает
+    /// с текущим (строгое сравнение, слабые ETag никогда не совпадают).
+    pub fn if_range_matches(&self, if_range: &str) -> bool {
+        let if_range = if_range.trim();
+        if if_range.starts_with("W/") {
+            return false;
+        }
+        if if_range.starts_with('"') {
+            return !self.etag.starts_with("W/") && if_range == self.etag;
+        }
+
+        match (parse_http_date(if_range), parse_http_date(&self.last_modified)) {
+            (Some(requested), Some(current)) => requested == current,
+            _ => false,
+        }
+    }
+}
+
+/// ETag без признака слабости `W/` для слабого сравнения
+fn opaque_tag(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+fn content_etag(path: &Path, metadata: &Metadata) -> std::io::Result<String> {
+    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+    let len = metadata.len();
+
+    if let Some((cached_modified, cached_len, etag)) = HASH_CACHE.lock().unwrap().get(path)
+        && *cached_modified == modified
+        && *cached_len == len
+    {
+        return Ok(etag.clone());
+    }
+
+    // Хешируем без блокировки кеша: большие файлы читаются долго
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    let digest = hasher.finalize();
+    let hex: String = digest[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+    let etag = format!("\"{}\"", hex);
+
+    HASH_CACHE
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf(), (modified, len, etag.clone()));
+
+    Ok(etag)
+}
+
+pub fn format_http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format(HTTP_DATE_FORMAT).to_string()
+}
+
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    NaiveDateTime::parse_from_str(value.trim(), HTTP_DATE_FORMAT)
+        .ok()
+        .map(|naive| naive.and_utc().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(tag: &str) -> CachedHash {
+        (UNIX_EPOCH, 0, tag.to_string())
+    }
+
+    fn validators() -> Validators {
+        Validators {
+            etag: "\"65920080-a\"".to_string(),
+            last_modified: "Mon, 01 Jan 2024 00:00:00 GMT".to_string(),
+        }
+    }
+
+    #[test]
+    fn not_modified_by_etag() {
+        let validators = validators();
+        assert!(validators.not_modified(Some("\"65920080-a\""), None));
+        assert!(validators.not_modified(Some("W/\"65920080-a\""), None));
+        assert!(validators.not_modified(Some("\"other\", \"65920080-a\""), None));
+        assert!(validators.not_modified(Some("\"65920080-a-gzip\""), None));
+        assert!(validators.not_modified(Some("*"), None));
+        assert!(!validators.not_modified(Some("\"other\""), None));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() {
+        let validators = validators();
+        let later = "Tue, 02 Jan 2024 00:00:00 GMT";
+        assert!(!validators.not_modified(Some("\"other\""), Some(later)));
+    }
+
+    #[test]
+    fn not_modified_by_date() {
+        let validators = validators();
+        assert!(validators.not_modified(None, Some("Mon, 01 Jan 2024 00:00:00 GMT")));
+        assert!(validators.not_modified(None, Some("Tue, 02 Jan 2024 00:00:00 GMT")));
+        assert!(!validators.not_modified(None, Some("Sun, 31 Dec 2023 00:00:00 GMT")));
+        assert!(!validators.not_modified(None, Some("yesterday")));
+        assert!(!validators.not_modified(None, None));
+    }
+
+    #[test]
+    fn if_range_requires_strong_exact_match() {
+        let validators = validators();
+        assert!(validators.if_range_matches("\"65920080-a\""));
+        assert!(!validators.if_range_matches("W/\"65920080-a\""));
+        assert!(!validators.if_range_matches("\"stale\""));
+        assert!(validators.if_range_matches("Mon, 01 Jan 2024 00:00:00 GMT"));
+        assert!(!validators.if_range_matches("Tue, 02 Jan 2024 00:00:00 GMT"));
+    }
+
+    #[test]
+    fn identical_content_has_identical_strong_etag() {
+        let dir = std::env::temp_dir().join(format!("strong-etag-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (first, second, other) = (dir.join("a.txt"), dir.join("b.txt"), dir.join("c.txt"));
+        std::fs::write(&first, "same content").unwrap();
+        std::fs::write(&second, "same content").unwrap();
+        std::fs::write(&other, "other content").unwrap();
+
+        let etag = |path: &Path| {
+            let metadata = std::fs::metadata(path).unwrap();
+            Validators::strong_for_file(path, &metadata).unwrap().etag
+        };
+        let (first, second, other) = (etag(&first), etag(&second), etag(&other));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn hash_cache_evicts_oldest_entry() {
+        let mut cache = HashCache::default();
+        for i in 0..HASH_CACHE_CAPACITY {
+            cache.insert(PathBuf::from(format!("/{}", i)), hash("old"));
+        }
+        // Обновление существующей записи не вытесняет другие
+        cache.insert(PathBuf::from("/0"), hash("updated"));
+        assert_eq!(cache.entries.len(), HASH_CACHE_CAPACITY);
+
+        cache.insert(PathBuf::from("/new"), hash("new"));
+        assert_eq!(cache.entries.len(), HASH_CACHE_CAPACITY);
+        assert!(cache.get(Path::new("/0")).is_none());
+        assert!(cache.get(Path::new("/1")).is_some());
+        assert_eq!(cache.get(Path::new("/new")).unwrap().2, "new");
+    }
+
+    #[test]
+    fn metadata_etag_is_weak() {
+        let metadata = std::fs::metadata(std::env::current_exe().unwrap()).unwrap();
+        let validators = Validators::from_metadata(&metadata);
+        assert!(validators.etag.starts_with("W/\""), "{}", validators.etag);
+        assert!(validators.gzip_etag().starts_with("W/\""));
+        assert!(validators.gzip_etag().ends_with("-gzip\""));
+
+        let strong = validators.etag.trim_start_matches("W/").to_string();
+        assert!(validators.not_modified(Some(&validators.etag), None));
+        assert!(validators.not_modified(Some(&strong), None));
+        assert!(!validators.if_range_matches(&validators.etag));
+        assert!(!validators.if_range_matches(&strong));
+        assert!(validators.if_range_matches(&validators.last_modified));
+    }
+}
@@ -0,0 +1,128 @@
+use threadpool::ThreadPool;
+
+use super::config::PoolModel;
+
+/// Вид задачи: чтение/разбор запроса или отправка ответа
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Job {
+    Read,
+    Write,
+}
+
+/// Пул рабочих потоков. В модели `split` чтение и запись обслуживают разные
+/// группы потоков, поэтому медленные клиенты на отправке не мешают разбору
+/// новых запросов
+pub struct WorkerPool {
+    read: ThreadPool,
+    write: Option<ThreadPool>,
+}
+
+impl WorkerPool {
+    pub fn new(model: PoolModel, threads: usize) -> Self {
+        match model {
+            PoolModel::Shared => Self {
+                read: ThreadPool::with_name("worker".to_string(), threads),
+                write: None,
+            },
+            PoolModel::Split => {
+                let read_threads = (threads / 2).max(1);
+                let write_threads = threads.saturating_sub(read_threads).max(1);
+                Self {
+                    read: ThreadPool::with_name("reader".to_string(), read_threads),
+                    write: Some(ThreadPool::with_name("writer".to_string(), write_threads)),
+                }
+            }
+        }
+    }
+
+    fn group(&self, job: Job) -> &ThreadPool {
+        match (job, &self.write) {
+            (Job::Write, Some(write)) => write,
+            _ => &self.read,
+        }
+    }
+
+    pub fn execute<F>(&self, job: Job, task: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.group(job).execute(task);
+    }
+
+    /// Все потоки группы заняты и её очередь достигла `max_queued`
+    pub fn saturated(&self, job: Job, max_queued: usize) -> bool {
+        let group = self.group(job);
+        group.active_count() >= group.max_count() && group.queued_count() >= max_queued
+    }
+
+    /// Дожидается завершения всех задач в очередях
+    pub fn join(&self) {
+        self.read.join();
+        if let Some(ref write) = self.write {
+            write.join();
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.read.max_count() + self.write.as_ref().map_or(0, ThreadPool::max_count)
+    }
+
+    /// Загрузка групп для логов и отладочной страницы: (имя, потоки, активные, в очереди)
+    pub fn stats(&self) -> Vec<(&'static str, usize, usize, usize)> {
+        let mut stats = Vec::new();
+        let read_name = if self.write.is_some() { "read" } else { "shared" };
+        stats.push(group_stats(read_name, &self.read));
+        if let Some(ref write) = self.write {
+            stats.push(group_stats("write", write));
+        }
+        stats
+    }
+
+    pub fn stats_json(&self) -> String {
+        let groups: Vec<String> = self
+            .stats()
+            .into_iter()
+            .map(|(name, threads, active, queued)| {
+                format!(
+                    "{{\"group\":\"{}\",\"threads\":{},\"active\":{},\"queued\":{}}}",
+                    name, threads, active, queued
+                )
+            })
+            .collect();
+        format!("[{}]", groups.join(","))
+    }
+}
+
+fn group_stats(name: &'static str, pool: &ThreadPool) -> (&'static str, usize, usize, usize) {
+    (name, pool.max_count(), pool.active_count(), pool.queued_count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn reads_progress_while_writers_are_saturated() {
+        let pool = WorkerPool::new(PoolModel::Split, 2);
+        let (started, wait_started) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel::<()>();
+
+        pool.execute(Job::Write, move || {
+            started.send(()).unwrap();
+            let _ = wait_release.recv();
+        });
+        wait_started.recv().unwrap();
+        pool.execute(Job::Write, || {});
+        assert!(pool.saturated(Job::Write, 1));
+
+        let (done, wait_done) = mpsc::channel();
+        pool.execute(Job::Read, move || done.send(()).unwrap());
+        assert!(wait_done.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(!pool.saturated(Job::Read, 1));
+
+        release.send(()).unwrap();
+        pool.join();
+    }
+}
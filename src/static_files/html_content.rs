@@ -95,3 +95,16 @@ pub fn get_html() -> String {
 </body>
 </html>")
 }
+
+pub fn get_blank_html() -> String {
+    String::from("<!DOCTYPE html>
+<html>
+<head>
+    <meta charset=\"UTF-8\">
+    <title></title>
+</head>
+<body>
+</body>
+</html>
+")
+}
@@ -1,2 +1,4 @@
+pub mod autoindex_content;
 pub mod css_content;
 pub mod html_content;
+pub mod robots_content;
@@ -0,0 +1,7 @@
+pub fn get_robots(allow: bool) -> String {
+    if allow {
+        String::from("User-agent: *\nDisallow:\n")
+    } else {
+        String::from("User-agent: *\nDisallow: /\n")
+    }
+}
@@ -0,0 +1,465 @@
+//! Проверки через настоящий сокет: каждый тест запускает собранный сервер
+//! на свободном порту со своим корнем документов во временной директории
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+struct Server {
+    child: Child,
+    port: u16,
+    dir: PathBuf,
+}
+
+impl Server {
+    /// Запускает сервер с файлами `files` (путь относительно корня) и
+    /// дополнительными параметрами командной строки
+    fn start(files: &[(&str, &[u8])], args: &[&str]) -> Self {
+        Self::spawn(files, args, None)
+    }
+
+    /// То же, что `start`, но с мягким RLIMIT_NOFILE `limit` у процесса сервера
+    fn with_open_files_limit(files: &[(&str, &[u8])], args: &[&str], limit: u64) -> Self {
+        Self::spawn(files, args, Some(limit))
+    }
+
+    fn spawn(files: &[(&str, &[u8])], args: &[&str], open_files_limit: Option<u64>) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "static-server-test-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        let root = dir.join("root");
+        for (path, content) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        std::fs::create_dir_all(&root).unwrap();
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut command = Command::new(env!("CARGO_BIN_EXE_static-server"));
+        if let Some(limit) = open_files_limit {
+            // setrlimit безопасен между fork и exec
+            unsafe {
+                command.pre_exec(move || {
+                    let mut rlimit = libc::rlimit {
+                        rlim_cur: 0,
+                        rlim_max: 0,
+                    };
+                    if libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    rlimit.rlim_cur = limit as libc::rlim_t;
+                    if libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        let child = command
+            .current_dir(&dir)
+            .env_remove("PORT")
+            .env_remove("RUST_LOG")
+            .args([
+                "--port",
+                &port.to_string(),
+                "--no-default-files",
+                "-d",
+                "root",
+            ])
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let server = Self { child, port, dir };
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "server did not start"
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        server
+    }
+
+    fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream
+    }
+
+    /// Ждёт появления `needle` в server.log и возвращает весь лог: запись
+    /// access-лога делается уже после отправки ответа
+    fn wait_for_log(&self, needle: &str) -> String {
+        let started = Instant::now();
+        loop {
+            let log = std::fs::read_to_string(self.dir.join("server.log")).unwrap_or_default();
+            if log.contains(needle) {
+                return log;
+            }
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "{:?} not logged:\n{}",
+                needle,
+                log
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn read_to_close(stream: &mut TcpStream) -> Vec<u8> {
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    response
+}
+
+/// Читает один ответ с Content-Length, не дожидаясь закрытия соединения
+fn read_response(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        assert_eq!(
+            stream.read(&mut byte).unwrap(),
+            1,
+            "connection closed mid-response"
+        );
+        response.push(byte[0]);
+    }
+    let headers = String::from_utf8(response.clone()).unwrap();
+    let length: usize = headers
+        .lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .map_or(0, |value| value.parse().unwrap());
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body).unwrap();
+    response.extend_from_slice(&body);
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+fn count(haystack: &[u8], needle: &str) -> usize {
+    haystack
+        .windows(needle.len())
+        .filter(|window| *window == needle.as_bytes())
+        .count()
+}
+
+#[test]
+fn pipelined_requests_get_a_response_each() {
+    let server = Server::start(&[("a.txt", b"first"), ("b.txt", b"second")], &[]);
+
+    let mut stream = server.connect();
+    stream
+        .write_all(
+            b"GET /a.txt HTTP/1.1\r\nHost: test\r\n\r\n\
+              GET /b.txt HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+    let _ = stream.shutdown(Shutdown::Write);
+    let response = read_to_close(&mut stream);
+
+    assert_eq!(count(&response, "HTTP/1.1 200 OK\r\n"), 2);
+    let text = String::from_utf8_lossy(&response);
+    let first = text.find("\r\n\r\nfirst").expect("first body");
+    let second = text.find("\r\n\r\nsecond").expect("second body");
+    assert!(first < second);
+}
+
+#[test]
+fn debug_page_requires_admin_credentials_when_configured() {
+    let request = |server: &Server, authorization: &str| {
+        let mut stream = server.connect();
+        write!(
+            stream,
+            "GET /debug HTTP/1.1\r\nHost: test\r\n{}Connection: close\r\n\r\n",
+            authorization
+        )
+        .unwrap();
+        String::from_utf8(read_to_close(&mut stream)).unwrap()
+    };
+    let debug = ["--debug-path", "/debug"];
+
+    let open = Server::start(&[], &debug);
+    assert!(request(&open, "").starts_with("HTTP/1.1 200 OK\r\n"));
+
+    let protected = Server::start(
+        &[],
+        &[&debug[..], &["--admin-auth", "admin:secret"]].concat(),
+    );
+    let denied = request(&protected, "");
+    assert!(denied.starts_with("HTTP/1.1 401 "), "{}", denied);
+    assert!(denied.contains("\r\nWWW-Authenticate: Basic realm=\"admin\"\r\n"));
+    let wrong = request(&protected, "Authorization: Basic YWRtaW46d3Jvbmc=\r\n");
+    assert!(wrong.starts_with("HTTP/1.1 401 "), "{}", wrong);
+    let allowed = request(&protected, "Authorization: Basic YWRtaW46c2VjcmV0\r\n");
+    assert!(allowed.starts_with("HTTP/1.1 200 OK\r\n"), "{}", allowed);
+    assert!(allowed.contains("\"connections\""), "{}", allowed);
+}
+
+#[test]
+fn access_log_line_includes_referer_and_user_agent() {
+    let server = Server::start(&[("a.txt", b"hello")], &["-v"]);
+
+    let mut stream = server.connect();
+    stream
+        .write_all(
+            b"GET /a.txt HTTP/1.1\r\nHost: test\r\nReferer: http://example.com/page\r\n\
+              User-Agent: test-agent/1.0\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+    read_to_close(&mut stream);
+    let mut stream = server.connect();
+    stream.write_all(b"GET /a.txt HTTP/1.0\r\n\r\n").unwrap();
+    read_to_close(&mut stream);
+
+    server.wait_for_log("\"GET /a.txt HTTP/1.1\" 200 ");
+    let log = server.wait_for_log("\"GET /a.txt HTTP/1.0\" 200 ");
+    let lines: Vec<&str> = log
+        .lines()
+        .filter(|line| line.contains("\"GET /a.txt"))
+        .collect();
+    assert_eq!(lines.len(), 2, "{}", log);
+    assert!(
+        lines[0].ends_with("\"http://example.com/page\" \"test-agent/1.0\""),
+        "{}",
+        lines[0]
+    );
+    assert!(lines[1].ends_with("\"-\" \"-\""), "{}", lines[1]);
+}
+
+#[test]
+fn keepalive_max_closes_after_the_last_allowed_request() {
+    let server = Server::start(&[("a.txt", b"hello")], &["--keepalive-max", "3"]);
+    let mut stream = server.connect();
+
+    for remaining in [2, 1] {
+        stream
+            .write_all(b"GET /a.txt HTTP/1.1\r\nHost: test\r\n\r\n")
+            .unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(
+            response.contains(&format!("\r\nKeep-Alive: timeout=5, max={}\r\n", remaining)),
+            "{}",
+            response
+        );
+    }
+
+    stream
+        .write_all(b"GET /a.txt HTTP/1.1\r\nHost: test\r\n\r\n")
+        .unwrap();
+    let last = read_response(&mut stream);
+    assert!(last.contains("\r\nConnection: close\r\n"), "{}", last);
+    assert!(last.ends_with("\r\n\r\nhello"));
+
+    // Следующий запрос уже не обслуживается: сервер закрыл сокет
+    let _ = stream.write_all(b"GET /a.txt HTTP/1.1\r\nHost: test\r\n\r\n");
+    let mut rest = Vec::new();
+    // Закрытие видно как EOF или как сброс, если запрос пришёл уже после него;
+    // тайм-аут чтения означал бы, что соединение всё ещё открыто
+    match stream.read_to_end(&mut rest) {
+        Ok(_) => assert!(rest.is_empty(), "{}", String::from_utf8_lossy(&rest)),
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset, "{}", e),
+    }
+}
+
+#[test]
+fn response_bytes_are_counted_by_content_type() {
+    // Тело в ASCII: read_response возвращает ответ строкой
+    let image = [b'x'; 300];
+    let server = Server::start(
+        &[("logo.png", &image), ("page.html", b"<html></html>")],
+        &["--debug-path", "/debug"],
+    );
+    let mut stream = server.connect();
+    let mut get = |path: &str| {
+        write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+        read_response(&mut stream)
+    };
+    // Запросы идут по одному соединению: следующий обрабатывается только
+    // после того, как предыдущий ответ отправлен и учтён
+    let counter = |debug: &str, category: &str| -> usize {
+        let totals = &debug[debug.find("\"http_response_bytes_total\":{").unwrap()..];
+        let value = &totals[totals.find(&format!("\"{}\":", category)).unwrap()..];
+        let value = &value[category.len() + 3..];
+        let end = value.find(|c: char| !c.is_ascii_digit()).unwrap();
+        value[..end].parse().unwrap()
+    };
+
+    let before = get("/debug");
+    let image_response = get("/logo.png");
+    let html_response = get("/page.html");
+    let after = get("/debug");
+
+    assert!(image_response.contains("\r\nContent-Type: image/png\r\n"));
+    assert_eq!(
+        counter(&after, "image") - counter(&before, "image"),
+        image_response.len()
+    );
+    assert_eq!(
+        counter(&after, "html") - counter(&before, "html"),
+        html_response.len()
+    );
+    assert_eq!(counter(&after, "script"), counter(&before, "script"));
+}
+
+#[test]
+fn large_file_survives_tiny_socket_buffers() {
+    let content: Vec<u8> = (0..3 * 1024 * 1024u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    let server = Server::start(
+        &[("big.bin", &content)],
+        &["--so-sndbuf", "4096", "--so-rcvbuf", "4096"],
+    );
+
+    let mut stream = server.connect();
+    stream
+        .write_all(b"GET /big.bin HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    // Медленное чтение мелкими порциями: запись на сервере постоянно
+    // упирается в заполненный буфер и завершается частично
+    let mut response = Vec::new();
+    let mut buf = [0u8; 1500];
+    loop {
+        let n = stream.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+        if response.len() < 256 * 1024 {
+            std::thread::sleep(Duration::from_micros(200));
+        }
+    }
+
+    let end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .expect("header section")
+        + 4;
+    let headers = String::from_utf8_lossy(&response[..end]);
+    assert!(headers.starts_with("HTTP/1.1 200 OK\r\n"), "{}", headers);
+    assert_eq!(response.len() - end, content.len());
+    assert!(response[end..] == content[..], "body differs from the file");
+}
+
+#[test]
+fn header_section_filling_the_request_buffer_is_answered() {
+    const BUFFER: usize = 8192;
+    let server = Server::start(&[("a.txt", b"hello")], &[]);
+    let request = |len: usize| {
+        let head = "GET /a.txt HTTP/1.1\r\nHost: test\r\nConnection: close\r\nX-Pad: ";
+        let pad = "a".repeat(len - head.len() - 4);
+        format!("{}{}\r\n\r\n", head, pad).into_bytes()
+    };
+
+    // Разделитель заканчивается ровно на последнем байте буфера
+    let mut stream = server.connect();
+    stream.write_all(&request(BUFFER)).unwrap();
+    let response = String::from_utf8(read_to_close(&mut stream)).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+    // Буфер заполнен, а последнего байта разделителя нет: без ответа 431
+    // соединение зависло бы в ожидании, и чтение упёрлось бы в тайм-аут
+    let mut stream = server.connect();
+    stream.write_all(&request(BUFFER + 1)[..BUFFER]).unwrap();
+    let response = String::from_utf8(read_to_close(&mut stream)).unwrap();
+    assert!(response.starts_with("HTTP/1.1 431 "), "{}", response);
+}
+
+#[test]
+fn connections_are_refused_within_fd_headroom() {
+    let server =
+        Server::with_open_files_limit(&[("a.txt", b"hello")], &["-v", "--fd-headroom", "24"], 64);
+    let request = b"GET /a.txt HTTP/1.1\r\nHost: test\r\n\r\n";
+
+    // Лимит 64 при запасе 24: принимается 40 соединений, остальным 503
+    let mut accepted = Vec::new();
+    for _ in 0..40 {
+        let mut stream = server.connect();
+        stream.write_all(request).unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        accepted.push(stream);
+    }
+    for _ in 0..5 {
+        let mut stream = server.connect();
+        let response = String::from_utf8(read_to_close(&mut stream)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+        assert!(response.contains("\r\nRetry-After: 1\r\n"), "{}", response);
+    }
+
+    // Отказ случился раньше EMFILE: принятые соединения не закрывались
+    // ради освобождения дескрипторов и продолжают обслуживаться
+    for stream in &mut accepted {
+        stream.write_all(request).unwrap();
+        let response = read_response(stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+    let log = server.wait_for_log("Within --fd-headroom of the descriptor limit 64");
+    assert!(!log.contains("Out of file descriptors"), "{}", log);
+}
+
+#[test]
+fn error_responses_log_one_line_with_request_and_status() {
+    let server = Server::start(&[("a.txt", b"hello")], &["-v"]);
+    let requests = [
+        ("GET /missing.txt HTTP/1.1", "404"),
+        ("GET /../etc/passwd HTTP/1.1", "403"),
+        ("BREW /pot HTTP/1.1", "501"),
+        ("GET /a.txt HTTP/1.1", "200"),
+    ];
+    for (request_line, status) in requests {
+        let mut stream = server.connect();
+        write!(
+            stream,
+            "{}\r\nHost: test\r\nConnection: close\r\n\r\n",
+            request_line
+        )
+        .unwrap();
+        let response = String::from_utf8(read_to_close(&mut stream)).unwrap();
+        assert!(
+            response.starts_with(&format!("HTTP/1.1 {} ", status)),
+            "{}",
+            response
+        );
+    }
+
+    // Ошибка журналируется ещё до отправки ответа, а запрос /a.txt идёт
+    // последним: когда видна его строка, остальные уже в журнале
+    let log = server.wait_for_log("\"GET /a.txt HTTP/1.1\" 200 ");
+    for (request_line, status) in &requests[..3] {
+        let line = format!("\"{}\" {} on fd ", request_line, status);
+        assert_eq!(log.matches(&line).count(), 1, "{}\n{}", line, log);
+    }
+    assert!(
+        !log.contains("\"GET /a.txt HTTP/1.1\" 200 on fd"),
+        "{}",
+        log
+    );
+}